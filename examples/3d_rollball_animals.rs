@@ -1,18 +1,22 @@
-use fcsrv::{model::ModelType, BootArgs};
-use ort::AllocatorType;
+use fcsrv::{
+    model::{inference::Allocator, ModelType},
+    ModelArgs,
+};
 
 fn main() {
-    fcsrv::model::init_predictor(&BootArgs {
-        debug: false,
-        bind: "0.0.0.0:8000".parse().unwrap(),
-        tls_cert: None,
-        tls_key: None,
-        api_key: None,
-        multi_image_limit: 1,
+    fcsrv::model::init_predictor(&ModelArgs {
+        tile_cache_distance: None,
+        tile_cache_size: 4096,
         update_check: false,
         model_dir: None,
+        model_config: None,
         num_threads: 4,
-        allocator: AllocatorType::Arena,
+        allocator: Allocator::Arena,
+        session_pool_size: 1,
+        warmup: false,
+        prefer_quantized: false,
+        force_quantized: false,
+        resize: None,
     })
     .unwrap();
 
@@ -24,7 +28,8 @@ fn main() {
             .unwrap();
     let guess = predictor
         .predict(image::load_from_memory(&image_file).unwrap())
-        .unwrap();
+        .unwrap()
+        .answer;
     assert_eq!(guess, 3);
 
     // Read image file images/3d_rollball_animals/1a03913c-61e1-4c95-a9c6-e45bbc419ee4-0_3.jpg
@@ -33,7 +38,8 @@ fn main() {
             .unwrap();
     let guess = predictor
         .predict(image::load_from_memory(&image_file).unwrap())
-        .unwrap();
+        .unwrap()
+        .answer;
     assert_eq!(guess, 3);
 
     // Read image file images/3d_rollball_animals/1a03913c-61e1-4c95-a9c6-e45bbc419ee4-1_3.jpg
@@ -42,7 +48,8 @@ fn main() {
             .unwrap();
     let guess = predictor
         .predict(image::load_from_memory(&image_file).unwrap())
-        .unwrap();
+        .unwrap()
+        .answer;
     assert_eq!(guess, 3);
 
     // Read image file images/3d_rollball_animals/1a03913c-61e1-4c95-a9c6-e45bbc419ee4-2_2.jpg
@@ -51,6 +58,7 @@ fn main() {
             .unwrap();
     let guess = predictor
         .predict(image::load_from_memory(&image_file).unwrap())
-        .unwrap();
+        .unwrap()
+        .answer;
     assert_eq!(guess, 2);
 }
@@ -0,0 +1,25 @@
+//! Local stand-in for an OpenTelemetry collector, prints every OTLP/HTTP trace export.
+//!
+//! cargo run --example otlp_collector
+//! cargo run --features otlp -- run --otlp-endpoint http://127.0.0.1:4318
+
+use warp::{hyper::body::Bytes, Filter};
+
+#[tokio::main]
+async fn main() {
+    let traces = warp::path!("v1" / "traces")
+        .and(warp::post())
+        .and(warp::header::optional::<String>("content-type"))
+        .and(warp::body::bytes())
+        .map(|content_type: Option<String>, body: Bytes| {
+            println!(
+                "received trace export: {} bytes ({})",
+                body.len(),
+                content_type.unwrap_or_default()
+            );
+            warp::reply()
+        });
+
+    println!("OTLP collector listening on 127.0.0.1:4318");
+    warp::serve(traces).run(([127, 0, 0, 1], 4318)).await;
+}
@@ -1,19 +1,23 @@
-use fcsrv::{model::ModelType, BootArgs};
-use ort::AllocatorType;
+use fcsrv::{
+    model::{inference::Allocator, ModelType},
+    ModelArgs,
+};
 use std::path::PathBuf;
 
 fn main() {
-    fcsrv::model::init_predictor(&BootArgs {
-        debug: false,
-        bind: "0.0.0.0:8000".parse().unwrap(),
-        tls_cert: None,
-        tls_key: None,
-        api_key: None,
-        multi_image_limit: 1,
+    fcsrv::model::init_predictor(&ModelArgs {
+        tile_cache_distance: None,
+        tile_cache_size: 4096,
         update_check: false,
         model_dir: Some(PathBuf::from("models")),
+        model_config: None,
         num_threads: 4,
-        allocator: AllocatorType::Arena,
+        allocator: Allocator::Arena,
+        session_pool_size: 1,
+        warmup: false,
+        prefer_quantized: false,
+        force_quantized: false,
+        resize: None,
     })
     .unwrap();
 
@@ -26,7 +30,8 @@ fn main() {
     .unwrap();
     let guess = predictor
         .predict(image::load_from_memory(&image_file).unwrap())
-        .unwrap();
+        .unwrap()
+        .answer;
     assert_eq!(guess, 1);
 
     // Read image file images/shadows/0d1dd3dcfa12b88027135334db1b08a824adfbc0688200324d935043e121e7b7_3.jpg
@@ -36,7 +41,8 @@ fn main() {
     .unwrap();
     let guess = predictor
         .predict(image::load_from_memory(&image_file).unwrap())
-        .unwrap();
+        .unwrap()
+        .answer;
     assert_eq!(guess, 3);
 
     // Read image file images/shadows/1d5e432bffabb5d6a32cf06381d43003c2d1f4ad380ffe464a6ae7cf60db4e74_2.jpg
@@ -46,7 +52,8 @@ fn main() {
     .unwrap();
     let guess = predictor
         .predict(image::load_from_memory(&image_file).unwrap())
-        .unwrap();
+        .unwrap()
+        .answer;
     assert_eq!(guess, 2);
 
     // Read image file images/shadows/1ee9fb5afa79bcc27c9f5e01b2e995b7db9fb6fae41b97912f7a5df6f3bf7d14_1.jpg
@@ -56,7 +63,8 @@ fn main() {
     .unwrap();
     let guess = predictor
         .predict(image::load_from_memory(&image_file).unwrap())
-        .unwrap();
+        .unwrap()
+        .answer;
     assert_eq!(guess, 1);
 
     // Read image file images/shadows/2d3d456cf6938f721685d73d94bc00ff511fd23462c0a55ab897dae0d3617e94_0.jpg
@@ -66,6 +74,7 @@ fn main() {
     .unwrap();
     let guess = predictor
         .predict(image::load_from_memory(&image_file).unwrap())
-        .unwrap();
+        .unwrap()
+        .answer;
     assert_eq!(guess, 0);
 }
@@ -19,3 +19,18 @@ static ALLOC: snmalloc_rs::SnMalloc = snmalloc_rs::SnMalloc;
 #[cfg(feature = "rpmalloc")]
 #[global_allocator]
 static ALLOC: rpmalloc::RpMalloc = rpmalloc::RpMalloc;
+
+/// Name of the global allocator
+pub const NAME: &str = if cfg!(feature = "jemalloc") {
+    "jemalloc"
+} else if cfg!(feature = "tcmalloc") {
+    "tcmalloc"
+} else if cfg!(feature = "mimalloc") {
+    "mimalloc"
+} else if cfg!(feature = "snmalloc") {
+    "snmalloc"
+} else if cfg!(feature = "rpmalloc") {
+    "rpmalloc"
+} else {
+    "system"
+};
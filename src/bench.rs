@@ -0,0 +1,230 @@
+//! In-process latency and throughput benchmark
+
+use anyhow::Result;
+use image::DynamicImage;
+use serde::Serialize;
+use std::{
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    alloc, eval,
+    model::{self, inference, Predictor},
+    BenchArgs, ModelArgs,
+};
+
+#[derive(Debug, Serialize)]
+struct Report {
+    version: &'static str,
+    /// global allocator feature of the build
+    global_allocator: &'static str,
+    /// inference backend feature of the build
+    backend: &'static str,
+    runs: Vec<Run>,
+}
+
+/// Result of one model, allocator, thread count and concurrency combination
+#[derive(Debug, Serialize)]
+struct Run {
+    #[serde(rename = "type")]
+    typed: &'static str,
+    allocator: &'static str,
+    num_threads: u16,
+    concurrency: usize,
+    images: usize,
+    p50_ms: f64,
+    p95_ms: f64,
+    p99_ms: f64,
+    mean_ms: f64,
+    images_per_sec: f64,
+    /// peak resident set size during the run, Linux only
+    #[serde(skip_serializing_if = "Option::is_none")]
+    peak_rss_bytes: Option<u64>,
+}
+
+/// Sweep the corpus models over the allocator, thread and concurrency choices
+pub fn bench(args: BenchArgs) -> Result<()> {
+    let mut runs = Vec::new();
+    for (model_type, dir) in eval::corpus_models(&args.dir, &args.types)? {
+        let images = eval::corpus_images(&dir)?
+            .iter()
+            .map(|file| Ok(image::open(file)?))
+            .collect::<Result<Vec<DynamicImage>>>()?;
+        if images.is_empty() {
+            continue;
+        }
+
+        for &allocator in &args.allocator {
+            for &num_threads in &args.num_threads {
+                let model_args = ModelArgs {
+                    tile_cache_distance: None,
+                    tile_cache_size: 0,
+                    update_check: args.update_check,
+                    model_dir: args.model_dir.clone(),
+                    model_config: args.model_config.clone(),
+                    num_threads,
+                    allocator,
+                    session_pool_size: 1,
+                    warmup: false,
+                    prefer_quantized: false,
+                    force_quantized: false,
+                    resize: None,
+                };
+                let predictor = model::new_predictor(model_type, &model_args)?;
+                for image in images.iter().cycle().take(args.warmup) {
+                    predictor.predict(image.clone())?;
+                }
+
+                for &concurrency in &args.concurrency {
+                    eprintln!(
+                        "{}: allocator {}, {num_threads} threads, concurrency {concurrency}",
+                        model_type.as_str(),
+                        allocator.as_str()
+                    );
+                    reset_peak_rss();
+                    let start = Instant::now();
+                    let mut latencies =
+                        run(predictor.as_ref(), &images, args.requests, concurrency)?;
+                    let elapsed = start.elapsed();
+                    latencies.sort();
+
+                    runs.push(Run {
+                        typed: model_type.as_str(),
+                        allocator: allocator.as_str(),
+                        num_threads,
+                        concurrency,
+                        images: latencies.len(),
+                        p50_ms: percentile(&latencies, 50.0),
+                        p95_ms: percentile(&latencies, 95.0),
+                        p99_ms: percentile(&latencies, 99.0),
+                        mean_ms: millis(latencies.iter().sum::<Duration>())
+                            / latencies.len().max(1) as f64,
+                        images_per_sec: latencies.len() as f64 / elapsed.as_secs_f64(),
+                        peak_rss_bytes: peak_rss(),
+                    });
+                }
+            }
+        }
+    }
+
+    let report = Report {
+        version: env!("CARGO_PKG_VERSION"),
+        global_allocator: alloc::NAME,
+        backend: inference::BACKEND,
+        runs,
+    };
+    println!("{}", serde_json::to_string_pretty(&report)?);
+    Ok(())
+}
+
+/// Predict `requests` images from `concurrency` threads, returns every latency
+fn run(
+    predictor: &dyn Predictor,
+    images: &[DynamicImage],
+    requests: usize,
+    concurrency: usize,
+) -> Result<Vec<Duration>> {
+    let next = AtomicUsize::new(0);
+    thread::scope(|scope| {
+        let workers = (0..concurrency.max(1))
+            .map(|_| {
+                scope.spawn(|| -> Result<Vec<Duration>> {
+                    let mut latencies = Vec::new();
+                    loop {
+                        let index = next.fetch_add(1, Ordering::Relaxed);
+                        if index >= requests {
+                            return Ok(latencies);
+                        }
+                        // The predictor consumes the image, clone outside of the measurement
+                        let image = images[index % images.len()].clone();
+                        let start = Instant::now();
+                        predictor.predict(image)?;
+                        latencies.push(start.elapsed());
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let mut latencies = Vec::with_capacity(requests);
+        for worker in workers {
+            let worker = worker
+                .join()
+                .map_err(|_| anyhow::anyhow!("benchmark worker panicked"))?;
+            latencies.extend(worker?);
+        }
+        Ok(latencies)
+    })
+}
+
+/// Nearest-rank percentile of sorted latencies, in milliseconds
+fn percentile(sorted: &[Duration], percent: f64) -> f64 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+    let rank = ((percent / 100.0) * sorted.len() as f64).ceil() as usize;
+    millis(sorted[rank.clamp(1, sorted.len()) - 1])
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+/// Reset the peak resident set size, see `clear_refs` in proc(5)
+#[cfg(target_os = "linux")]
+fn reset_peak_rss() {
+    if let Err(err) = std::fs::write("/proc/self/clear_refs", "5") {
+        tracing::debug!("failed to reset peak RSS: {err}");
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn reset_peak_rss() {}
+
+/// Peak resident set size, `VmHWM` of `/proc/self/status`
+#[cfg(target_os = "linux")]
+fn peak_rss() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let kb = status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmHWM:"))?
+        .trim()
+        .trim_end_matches("kB")
+        .trim()
+        .parse::<u64>()
+        .ok()?;
+    Some(kb * 1024)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn peak_rss() -> Option<u64> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn latencies(millis: &[u64]) -> Vec<Duration> {
+        millis.iter().map(|&ms| Duration::from_millis(ms)).collect()
+    }
+
+    #[test]
+    fn nearest_rank_percentiles() {
+        let sorted = latencies(&[1, 2, 3, 4, 5, 6, 7, 8, 9, 10]);
+        assert_eq!(percentile(&sorted, 50.0), 5.0);
+        assert_eq!(percentile(&sorted, 90.0), 9.0);
+        assert_eq!(percentile(&sorted, 95.0), 10.0);
+        assert_eq!(percentile(&sorted, 100.0), 10.0);
+        assert_eq!(percentile(&sorted, 0.0), 1.0);
+    }
+
+    #[test]
+    fn percentile_of_few_latencies() {
+        assert_eq!(percentile(&[], 50.0), 0.0);
+        assert_eq!(percentile(&latencies(&[7]), 99.0), 7.0);
+        assert_eq!(percentile(&latencies(&[1, 20]), 50.0), 1.0);
+        assert_eq!(percentile(&latencies(&[1, 20]), 51.0), 20.0);
+    }
+}
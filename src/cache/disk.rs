@@ -0,0 +1,263 @@
+//! Persistent answer cache
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    path::Path,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use super::CacheKey;
+use crate::model::Prediction;
+
+/// Tree of cache key to entry
+const ANSWERS_TREE: &str = "answers";
+/// Tree of insertion sequence to cache key, oldest first
+const ORDER_TREE: &str = "order";
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Entry {
+    prediction: Prediction,
+    /// insertion time, seconds since the Unix epoch
+    inserted: u64,
+    /// insertion sequence, key of the order tree
+    seq: u64,
+}
+
+/// Embedded on-disk key-value store backing the answer cache
+#[derive(Debug)]
+pub struct DiskCache {
+    db: sled::Db,
+    answers: sled::Tree,
+    order: sled::Tree,
+    size: usize,
+    /// number of answers, `Tree::len` scans the whole tree
+    entries: AtomicUsize,
+}
+
+/// Summary of the on-disk cache
+#[derive(Debug)]
+pub struct DiskCacheInfo {
+    pub entries: usize,
+    pub size_on_disk: u64,
+    /// entries per model type
+    pub models: BTreeMap<String, usize>,
+    pub oldest: Option<SystemTime>,
+    pub newest: Option<SystemTime>,
+}
+
+impl DiskCache {
+    /// Open the cache directory, keeping at most `size` entries
+    pub fn open(path: &Path, size: usize) -> Result<Self> {
+        let db = sled::open(path).map_err(|err| match err {
+            // sled locks the store exclusively and has no read-only mode
+            sled::Error::Io(err) if err.to_string().contains("could not acquire lock") => {
+                anyhow::anyhow!(
+                    "the cache at {} is in use by a running server, stop it first",
+                    path.display()
+                )
+            }
+            err => err.into(),
+        })?;
+        let answers = db.open_tree(ANSWERS_TREE)?;
+        Ok(Self {
+            entries: AtomicUsize::new(answers.len()),
+            answers,
+            order: db.open_tree(ORDER_TREE)?,
+            db,
+            size,
+        })
+    }
+
+    /// Get the cached prediction and its age
+    pub fn get(&self, key: &CacheKey) -> Result<Option<(Prediction, Duration)>> {
+        let Some(value) = self.answers.get(key_bytes(key))? else {
+            return Ok(None);
+        };
+        let entry: Entry = serde_json::from_slice(&value)?;
+        let age = Duration::from_secs(unix_now().saturating_sub(entry.inserted));
+        Ok(Some((entry.prediction, age)))
+    }
+
+    /// Cache the prediction, evicting the oldest entries when full
+    pub fn insert(&self, key: &CacheKey, prediction: Prediction) -> Result<()> {
+        let key = key_bytes(key);
+        let seq = self.db.generate_id()?;
+        let entry = Entry {
+            prediction,
+            inserted: unix_now(),
+            seq,
+        };
+
+        match self.answers.insert(&key, serde_json::to_vec(&entry)?)? {
+            Some(old) => {
+                let old: Entry = serde_json::from_slice(&old)?;
+                self.order.remove(old.seq.to_be_bytes())?;
+            }
+            None => {
+                self.entries.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.order.insert(seq.to_be_bytes(), key)?;
+
+        while self.entries.load(Ordering::Relaxed) > self.size {
+            match self.order.pop_min()? {
+                Some((_, key)) => {
+                    if self.answers.remove(key)?.is_some() {
+                        self.entries.fetch_sub(1, Ordering::Relaxed);
+                    }
+                }
+                None => break,
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove the entry
+    pub fn remove(&self, key: &CacheKey) -> Result<()> {
+        if let Some(old) = self.answers.remove(key_bytes(key))? {
+            self.entries.fetch_sub(1, Ordering::Relaxed);
+            let old: Entry = serde_json::from_slice(&old)?;
+            self.order.remove(old.seq.to_be_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Summarize the cache content
+    pub fn info(&self) -> Result<DiskCacheInfo> {
+        let mut models = BTreeMap::new();
+        let (mut oldest, mut newest) = (None::<u64>, None::<u64>);
+        for item in self.answers.iter() {
+            let (key, value) = item?;
+            let model = key.split(|&b| b == b':').next().unwrap_or_default();
+            *models
+                .entry(String::from_utf8_lossy(model).into_owned())
+                .or_insert(0) += 1;
+
+            let entry: Entry = serde_json::from_slice(&value)?;
+            oldest = Some(oldest.map_or(entry.inserted, |t| t.min(entry.inserted)));
+            newest = Some(newest.map_or(entry.inserted, |t| t.max(entry.inserted)));
+        }
+
+        Ok(DiskCacheInfo {
+            entries: self.answers.len(),
+            size_on_disk: self.db.size_on_disk()?,
+            models,
+            oldest: oldest.map(|t| UNIX_EPOCH + Duration::from_secs(t)),
+            newest: newest.map(|t| UNIX_EPOCH + Duration::from_secs(t)),
+        })
+    }
+
+    /// Remove every entry
+    pub fn clear(&self) -> Result<()> {
+        self.answers.clear()?;
+        self.order.clear()?;
+        self.entries.store(0, Ordering::Relaxed);
+        self.db.flush()?;
+        Ok(())
+    }
+}
+
+/// `<model type>:<sha256>`
+fn key_bytes(key: &CacheKey) -> Vec<u8> {
+    let model = key.model.as_str().as_bytes();
+    let mut bytes = Vec::with_capacity(model.len() + 1 + key.digest.len());
+    bytes.extend_from_slice(model);
+    bytes.push(b':');
+    bytes.extend_from_slice(&key.digest);
+    bytes
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::ModelType;
+
+    fn key(i: u8) -> CacheKey {
+        CacheKey {
+            model: ModelType::Shadows,
+            digest: [i; 32],
+        }
+    }
+
+    fn prediction(answer: usize) -> Prediction {
+        let mut scores = vec![0.0; 6];
+        scores[answer] = 1.0;
+        Prediction::from_scores(scores)
+    }
+
+    #[test]
+    fn evicts_the_oldest_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = DiskCache::open(dir.path(), 3).unwrap();
+        for i in 0..5 {
+            cache.insert(&key(i), prediction(i as usize)).unwrap();
+        }
+        assert_eq!(cache.info().unwrap().entries, 3);
+        assert!(cache.get(&key(0)).unwrap().is_none());
+        assert!(cache.get(&key(1)).unwrap().is_none());
+        assert_eq!(cache.get(&key(4)).unwrap().unwrap().0.answer, 4);
+    }
+
+    #[test]
+    fn replacing_an_entry_keeps_the_count() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = DiskCache::open(dir.path(), 2).unwrap();
+        cache.insert(&key(0), prediction(0)).unwrap();
+        cache.insert(&key(1), prediction(1)).unwrap();
+        cache.insert(&key(0), prediction(2)).unwrap();
+        assert_eq!(cache.info().unwrap().entries, 2);
+        assert_eq!(cache.get(&key(0)).unwrap().unwrap().0.answer, 2);
+
+        // key 1 is now the oldest
+        cache.insert(&key(3), prediction(3)).unwrap();
+        assert!(cache.get(&key(1)).unwrap().is_none());
+        assert!(cache.get(&key(0)).unwrap().is_some());
+    }
+
+    #[test]
+    fn locked_cache_is_in_use() {
+        let dir = tempfile::tempdir().unwrap();
+        let _cache = DiskCache::open(dir.path(), 2).unwrap();
+        let err = DiskCache::open(dir.path(), 2).unwrap_err();
+        assert!(
+            err.to_string().contains("in use by a running server"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn counts_existing_entries_on_open() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let cache = DiskCache::open(dir.path(), 10).unwrap();
+            for i in 0..4 {
+                cache.insert(&key(i), prediction(0)).unwrap();
+            }
+            cache.db.flush().unwrap();
+        }
+        // sled's flusher thread releases the file lock shortly after the drop
+        let cache = (0..50)
+            .find_map(|_| {
+                DiskCache::open(dir.path(), 2)
+                    .map_err(|_| std::thread::sleep(Duration::from_millis(20)))
+                    .ok()
+            })
+            .unwrap();
+        cache.insert(&key(9), prediction(0)).unwrap();
+        assert_eq!(cache.info().unwrap().entries, 2);
+
+        cache.remove(&key(9)).unwrap();
+        cache.clear().unwrap();
+        assert_eq!(cache.entries.load(Ordering::Relaxed), 0);
+    }
+}
@@ -0,0 +1,227 @@
+//! Answer cache
+
+pub mod disk;
+pub mod phash;
+
+use anyhow::Result;
+use image::DynamicImage;
+use lru::LruCache;
+use sha2::{Digest, Sha256};
+use std::{
+    num::NonZeroUsize,
+    sync::{atomic::Ordering, Mutex},
+    time::{Duration, Instant},
+};
+
+use self::disk::DiskCache;
+use crate::{
+    metrics::METRICS,
+    model::{self, ModelType, Prediction},
+    CacheArgs, CacheCommand,
+};
+
+/// Persistent cache directory inside the model directory
+pub const CACHE_DIR: &str = "cache";
+
+/// Cache key: model type plus the SHA-256 of the decoded pixels
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    pub model: ModelType,
+    pub digest: [u8; 32],
+}
+
+impl CacheKey {
+    /// Hash the decoded image, the dimensions are part of the digest
+    pub fn new(model: ModelType, image: &DynamicImage) -> Self {
+        let mut sha256 = Sha256::new();
+        sha256.update(image.width().to_le_bytes());
+        sha256.update(image.height().to_le_bytes());
+        sha256.update(format!("{:?}", image.color()).as_bytes());
+        sha256.update(image.as_bytes());
+        Self {
+            model,
+            digest: sha256.finalize().into(),
+        }
+    }
+
+    /// Hex encoded digest
+    pub fn hex(&self) -> String {
+        self.digest.iter().map(|b| format!("{b:02x}")).collect()
+    }
+}
+
+/// In-memory LRU cache of predictions with a time to live, optionally backed by disk
+#[derive(Debug)]
+pub struct AnswerCache {
+    ttl: Duration,
+    entries: Mutex<LruCache<CacheKey, (Prediction, Instant)>>,
+    disk: Option<DiskCache>,
+}
+
+impl AnswerCache {
+    /// Create a new cache, returns `None` if the size is zero
+    pub fn new(size: usize, ttl: Duration, disk: Option<DiskCache>) -> Option<Self> {
+        let size = NonZeroUsize::new(size)?;
+        Some(Self {
+            ttl,
+            entries: Mutex::new(LruCache::new(size)),
+            disk,
+        })
+    }
+
+    /// Get a cached prediction that has not expired
+    pub fn get(&self, key: &CacheKey) -> Option<Prediction> {
+        let mut prediction = {
+            let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+            match entries.get(key) {
+                Some((prediction, inserted)) if inserted.elapsed() < self.ttl => {
+                    Some(prediction.clone())
+                }
+                Some(_) => {
+                    entries.pop(key);
+                    None
+                }
+                None => None,
+            }
+        };
+
+        // The disk is read without holding the in-memory cache lock
+        if let (None, Some(disk)) = (prediction.as_ref(), self.disk.as_ref()) {
+            prediction = match disk.get(key) {
+                Ok(Some((cached, age))) if age < self.ttl => {
+                    let inserted = Instant::now().checked_sub(age).unwrap_or_else(Instant::now);
+                    self.entries
+                        .lock()
+                        .unwrap_or_else(|e| e.into_inner())
+                        .put(*key, (cached.clone(), inserted));
+                    Some(cached)
+                }
+                Ok(Some(_)) => {
+                    if let Err(err) = disk.remove(key) {
+                        tracing::warn!("failed to remove expired cache entry: {err}");
+                    }
+                    None
+                }
+                Ok(None) => None,
+                Err(err) => {
+                    tracing::warn!("failed to read persistent cache: {err}");
+                    None
+                }
+            };
+        }
+
+        if prediction.is_some() {
+            METRICS.cache_hits.fetch_add(1, Ordering::Relaxed);
+        } else {
+            METRICS.cache_misses.fetch_add(1, Ordering::Relaxed);
+        }
+        prediction
+    }
+
+    /// Cache the prediction
+    pub fn insert(&self, key: CacheKey, prediction: Prediction) {
+        if let Some(disk) = self.disk.as_ref() {
+            if let Err(err) = disk.insert(&key, prediction.clone()) {
+                tracing::warn!("failed to write persistent cache: {err}");
+            }
+        }
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        entries.put(key, (prediction, Instant::now()));
+    }
+}
+
+/// Inspect or clear the persistent answer cache
+pub fn cache(args: CacheArgs) -> Result<()> {
+    let path = model::model_dir(args.model_dir.as_deref()).join(CACHE_DIR);
+    if !path.exists() {
+        println!("no persistent cache at {}", path.display());
+        return Ok(());
+    }
+
+    let disk = DiskCache::open(&path, usize::MAX)?;
+    match args.command {
+        CacheCommand::Info => {
+            let info = disk.info()?;
+            println!("path: {}", path.display());
+            println!("entries: {}", info.entries);
+            println!("size on disk: {} bytes", info.size_on_disk);
+            if let (Some(oldest), Some(newest)) = (info.oldest, info.newest) {
+                println!("oldest: {}", humantime::format_rfc3339_seconds(oldest));
+                println!("newest: {}", humantime::format_rfc3339_seconds(newest));
+            }
+            for (model, entries) in info.models {
+                println!("  {model}: {entries}");
+            }
+        }
+        CacheCommand::Clear => {
+            let entries = disk.info()?.entries;
+            disk.clear()?;
+            println!("cleared {entries} entries from {}", path.display());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::RgbImage;
+
+    fn key(i: u8) -> CacheKey {
+        CacheKey {
+            model: ModelType::Shadows,
+            digest: [i; 32],
+        }
+    }
+
+    #[test]
+    fn zero_size_disables_the_cache() {
+        assert!(AnswerCache::new(0, Duration::from_secs(60), None).is_none());
+    }
+
+    #[test]
+    fn evicts_the_least_recently_used() {
+        let cache = AnswerCache::new(2, Duration::from_secs(60), None).unwrap();
+        cache.insert(key(0), Prediction::from_scores(vec![1.0, 0.0]));
+        cache.insert(key(1), Prediction::from_scores(vec![0.0, 1.0]));
+        assert_eq!(cache.get(&key(0)).unwrap().answer, 0);
+        cache.insert(key(2), Prediction::from_scores(vec![1.0, 0.0]));
+        assert!(cache.get(&key(1)).is_none());
+        assert!(cache.get(&key(0)).is_some());
+        assert!(cache.get(&key(2)).is_some());
+    }
+
+    #[test]
+    fn expired_entries_are_misses() {
+        let cache = AnswerCache::new(2, Duration::ZERO, None).unwrap();
+        cache.insert(key(0), Prediction::from_scores(vec![1.0, 0.0]));
+        assert!(cache.get(&key(0)).is_none());
+    }
+
+    #[test]
+    fn falls_back_to_the_disk() {
+        let dir = tempfile::tempdir().unwrap();
+        let ttl = Duration::from_secs(60);
+        let disk = DiskCache::open(dir.path(), 10).unwrap();
+        disk.insert(&key(0), Prediction::from_scores(vec![0.0, 1.0]))
+            .unwrap();
+        let cache = AnswerCache::new(2, ttl, Some(disk)).unwrap();
+        assert_eq!(cache.get(&key(0)).unwrap().answer, 1);
+        assert!(cache.get(&key(1)).is_none());
+    }
+
+    #[test]
+    fn key_covers_the_model_and_dimensions() {
+        let wide = DynamicImage::ImageRgb8(RgbImage::new(4, 2));
+        let tall = DynamicImage::ImageRgb8(RgbImage::new(2, 4));
+        let shadows = CacheKey::new(ModelType::Shadows, &wide);
+        assert_eq!(shadows, CacheKey::new(ModelType::Shadows, &wide));
+        assert_ne!(
+            shadows.digest,
+            CacheKey::new(ModelType::Shadows, &tall).digest
+        );
+        assert_ne!(shadows, CacheKey::new(ModelType::Penguin, &wide));
+        assert_eq!(shadows.hex().len(), 64);
+        assert_eq!(key(0xab).hex(), "ab".repeat(32));
+    }
+}
@@ -0,0 +1,169 @@
+//! Perceptual hash cache of tile scores
+
+use ndarray::{ArrayView4, Axis};
+use std::{
+    collections::VecDeque,
+    sync::{atomic::Ordering, RwLock},
+};
+
+use crate::metrics::METRICS;
+
+/// dHash grid width, one more column than bits per row
+const DHASH_WIDTH: usize = 9;
+/// dHash grid height
+const DHASH_HEIGHT: usize = 8;
+
+/// Difference hash of a preprocessed `(1, 3, height, width)` tile tensor
+pub fn dhash(tile: ArrayView4<f32>) -> u64 {
+    let tile = tile.index_axis(Axis(0), 0);
+    let (height, width) = (tile.shape()[1], tile.shape()[2]);
+
+    // Box-average the grayscale tile into a 9x8 grid
+    let mut grid = [[0f32; DHASH_WIDTH]; DHASH_HEIGHT];
+    for (gy, row) in grid.iter_mut().enumerate() {
+        let (y0, y1) = cell_range(gy, DHASH_HEIGHT, height);
+        for (gx, cell) in row.iter_mut().enumerate() {
+            let (x0, x1) = cell_range(gx, DHASH_WIDTH, width);
+            let mut sum = 0.0;
+            for y in y0..y1 {
+                for x in x0..x1 {
+                    sum +=
+                        0.299 * tile[[0, y, x]] + 0.587 * tile[[1, y, x]] + 0.114 * tile[[2, y, x]];
+                }
+            }
+            *cell = sum / ((y1 - y0) * (x1 - x0)) as f32;
+        }
+    }
+
+    let mut hash = 0u64;
+    for row in grid.iter() {
+        for x in 0..DHASH_WIDTH - 1 {
+            hash = (hash << 1) | (row[x] > row[x + 1]) as u64;
+        }
+    }
+    hash
+}
+
+/// Pixel range of a grid cell, never empty
+fn cell_range(index: usize, cells: usize, pixels: usize) -> (usize, usize) {
+    let start = index * pixels / cells;
+    let end = ((index + 1) * pixels / cells).max(start + 1);
+    (start, end.min(pixels))
+}
+
+/// Bounded cache of tile scores, looked up by Hamming distance.
+///
+/// Pair classifiers score a tile against the answer image, so an entry is keyed by
+/// both hashes; classifiers use `0` for the answer hash.
+#[derive(Debug)]
+pub struct TileCache {
+    max_distance: u32,
+    size: usize,
+    /// oldest first, lookups share the read lock
+    entries: RwLock<VecDeque<(u64, u64, f32)>>,
+}
+
+impl TileCache {
+    /// Create a new cache, returns `None` if the size is zero
+    pub fn new(size: usize, max_distance: u32) -> Option<Self> {
+        if size == 0 {
+            return None;
+        }
+        Some(Self {
+            max_distance,
+            size,
+            entries: RwLock::new(VecDeque::with_capacity(size)),
+        })
+    }
+
+    /// Get the score of the nearest cached tile within the maximum distance
+    pub fn get(&self, answer: u64, tile: u64) -> Option<f32> {
+        let entries = self.entries.read().unwrap_or_else(|e| e.into_inner());
+        let mut nearest = None;
+        for &(a, t, score) in entries.iter() {
+            let distance = (a ^ answer).count_ones().max((t ^ tile).count_ones());
+            if distance <= self.max_distance && !matches!(nearest, Some((d, _)) if distance >= d) {
+                nearest = Some((distance, score));
+                if distance == 0 {
+                    break;
+                }
+            }
+        }
+        let score = nearest.map(|(_, score)| score);
+
+        if score.is_some() {
+            METRICS.tile_cache_hits.fetch_add(1, Ordering::Relaxed);
+        } else {
+            METRICS.tile_cache_misses.fetch_add(1, Ordering::Relaxed);
+        }
+        score
+    }
+
+    /// Cache the tile score, evicting the oldest entry when full
+    pub fn insert(&self, answer: u64, tile: u64, score: f32) {
+        let mut entries = self.entries.write().unwrap_or_else(|e| e.into_inner());
+        if entries.len() >= self.size {
+            entries.pop_front();
+        }
+        entries.push_back((answer, tile, score));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::Array4;
+
+    /// Horizontal gradient tile, brighter to the right or to the left
+    fn gradient(rising: bool) -> Array4<f32> {
+        Array4::from_shape_fn((1, 3, 52, 52), |(_, _, _, x)| {
+            let x = if rising { x } else { 51 - x };
+            x as f32 / 51.0
+        })
+    }
+
+    #[test]
+    fn dhash_follows_the_gradient() {
+        // every cell is darker than its right neighbor, no bit is set
+        assert_eq!(dhash(gradient(true).view()), 0);
+        assert_eq!(dhash(gradient(false).view()), u64::MAX);
+    }
+
+    #[test]
+    fn dhash_ignores_small_changes() {
+        let tile = gradient(true);
+        let noisy = tile.mapv(|v| v + 0.001);
+        assert_eq!(dhash(tile.view()), dhash(noisy.view()));
+    }
+
+    #[test]
+    fn dhash_of_small_tiles() {
+        let tile = Array4::from_shape_fn((1, 3, 4, 4), |(_, _, y, x)| (x + y) as f32);
+        assert_eq!(dhash(tile.view()), 0);
+    }
+
+    #[test]
+    fn nearest_entry_within_distance() {
+        let cache = TileCache::new(8, 2).unwrap();
+        cache.insert(0, 0b0000, 1.0);
+        cache.insert(0, 0b0011, 2.0);
+        assert_eq!(cache.get(0, 0b0000), Some(1.0));
+        assert_eq!(cache.get(0, 0b0111), Some(2.0));
+        assert_eq!(cache.get(0, 0b1111), Some(2.0));
+        assert_eq!(cache.get(0, 0b1111_1100), None);
+        // both hashes must be close
+        assert_eq!(cache.get(0b1111, 0b0000), None);
+    }
+
+    #[test]
+    fn evicts_the_oldest_entry() {
+        let cache = TileCache::new(2, 0).unwrap();
+        cache.insert(0, 1, 1.0);
+        cache.insert(0, 2, 2.0);
+        cache.insert(0, 3, 3.0);
+        assert_eq!(cache.get(0, 1), None);
+        assert_eq!(cache.get(0, 2), Some(2.0));
+        assert_eq!(cache.get(0, 3), Some(3.0));
+        assert!(TileCache::new(0, 0).is_none());
+    }
+}
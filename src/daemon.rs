@@ -4,11 +4,10 @@ use std::{
     os::unix::fs::PermissionsExt,
     path::Path,
 };
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 use daemonize::Daemonize;
 
-use crate::{model, serve::Serve, BootArgs};
+use crate::{logging, serve::Serve, BootArgs};
 
 #[cfg(target_family = "unix")]
 pub(crate) const PID_PATH: &str = "/var/run/fcsrv.pid";
@@ -43,15 +42,8 @@ pub fn run(args: BootArgs) -> Result<()> {
         std::env::set_var("RUST_LOG", "info");
     }
     // Init tracing
-    tracing_subscriber::registry()
-        .with(
-            tracing_subscriber::EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| "RUST_LOG=info".into()),
-        )
-        .with(tracing_subscriber::fmt::layer())
-        .init();
-    // Init model
-    model::init_predictor(&args)?;
+    let _guard = logging::init(&args)?;
+    // The server loads the models once listening
     Serve::new(args).run()
 }
 
@@ -0,0 +1,248 @@
+//! Image decoding of task and command line input
+
+use anyhow::{Context, Result};
+use base64::{
+    alphabet,
+    engine::{general_purpose::GeneralPurpose, DecodePaddingMode, GeneralPurposeConfig},
+    Engine as _,
+};
+use image::{codecs::gif::GifDecoder, AnimationDecoder, DynamicImage, ImageFormat, Rgb, RgbImage};
+use std::io::Cursor;
+
+/// Padding is optional in both alphabets
+const PADDING: GeneralPurposeConfig =
+    GeneralPurposeConfig::new().with_decode_padding_mode(DecodePaddingMode::Indifferent);
+const STANDARD: GeneralPurpose = GeneralPurpose::new(&alphabet::STANDARD, PADDING);
+const URL_SAFE: GeneralPurpose = GeneralPurpose::new(&alphabet::URL_SAFE, PADDING);
+
+/// Parse a `#rrggbb` or `rrggbb` background color
+pub fn parse_color(s: &str) -> Result<Rgb<u8>> {
+    let hex = s.strip_prefix('#').unwrap_or(s);
+    if hex.len() != 6 || !hex.is_ascii() {
+        anyhow::bail!("invalid color {s:?}, expected #rrggbb");
+    }
+    let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16);
+    Ok(Rgb([channel(0)?, channel(2)?, channel(4)?]))
+}
+
+/// Decode a base64 image, with or without a `data:` URL prefix. Transparent pixels are
+/// flattened onto the background.
+#[tracing::instrument(level = "debug", skip_all)]
+pub fn decode_base64(base64_string: &str, background: Rgb<u8>) -> Result<DynamicImage> {
+    let (format, payload) = match base64_string.split_once(',') {
+        Some((header, payload)) => (data_url_format(header), payload),
+        None => (None, base64_string),
+    };
+
+    // Line-wrapped base64 is common, whitespace is never part of it
+    let payload = payload
+        .chars()
+        .filter(|c| !c.is_ascii_whitespace())
+        .collect::<String>();
+    let engine = if payload.contains(['-', '_']) {
+        &URL_SAFE
+    } else {
+        &STANDARD
+    };
+    let image_bytes = engine.decode(payload).context("invalid base64 image")?;
+
+    if let Some(format) = format {
+        let actual = image::guess_format(&image_bytes).context("unknown image format")?;
+        if actual != format {
+            anyhow::bail!(
+                "data URL type {} does not match the {:?} image data",
+                format.to_mime_type(),
+                actual
+            );
+        }
+    }
+    decode_image(&image_bytes, background)
+}
+
+/// Decode raw image bytes, or base64 text if they are not a known image format
+pub fn decode_bytes(bytes: &[u8], background: Rgb<u8>) -> Result<DynamicImage> {
+    if image::guess_format(bytes).is_ok() {
+        return decode_image(bytes, background);
+    }
+    decode_base64(std::str::from_utf8(bytes)?.trim(), background)
+}
+
+/// Image format of a `data:image/png;base64` URL header, the `data:` scheme is optional.
+/// `None` if the header does not name a known image type, the format is then guessed
+/// from the image data.
+fn data_url_format(header: &str) -> Option<ImageFormat> {
+    let header = header.trim();
+    let header = header.strip_prefix("data:").unwrap_or(header);
+    let mime = header.split(';').next().unwrap_or_default().trim();
+    match mime.to_ascii_lowercase().as_str() {
+        // common aliases unknown to `ImageFormat::from_mime_type`
+        "image/jpg" | "image/pjpeg" => Some(ImageFormat::Jpeg),
+        mime => ImageFormat::from_mime_type(mime),
+    }
+}
+
+fn decode_image(image_bytes: &[u8], background: Rgb<u8>) -> Result<DynamicImage> {
+    let image = match image::guess_format(image_bytes)? {
+        // The first frame composited on the full canvas
+        ImageFormat::Gif => {
+            let frame = GifDecoder::new(Cursor::new(image_bytes))?
+                .into_frames()
+                .next()
+                .context("GIF image has no frames")??;
+            DynamicImage::ImageRgba8(frame.into_buffer())
+        }
+        format => image::load_from_memory_with_format(image_bytes, format)?,
+    };
+    let image = flatten(image, background);
+    tracing::debug!(
+        width = image.width(),
+        height = image.height(),
+        "decoded image"
+    );
+    Ok(image)
+}
+
+/// Blend the transparent pixels onto the background
+fn flatten(image: DynamicImage, background: Rgb<u8>) -> DynamicImage {
+    if !image.color().has_alpha() {
+        return image;
+    }
+    let rgba = image.into_rgba8();
+    let mut flat = RgbImage::from_pixel(rgba.width(), rgba.height(), background);
+    for (pixel, source) in flat.pixels_mut().zip(rgba.pixels()) {
+        let alpha = source[3] as u32;
+        for c in 0..3 {
+            pixel[c] =
+                ((source[c] as u32 * alpha + pixel[c] as u32 * (255 - alpha) + 127) / 255) as u8;
+        }
+    }
+    DynamicImage::ImageRgb8(flat)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::engine::general_purpose;
+    use image::{ImageOutputFormat, Rgba, RgbaImage};
+
+    const WHITE: Rgb<u8> = Rgb([255, 255, 255]);
+
+    fn encoded(format: ImageOutputFormat) -> Vec<u8> {
+        let image = DynamicImage::ImageRgb8(RgbImage::from_pixel(4, 3, Rgb([10, 20, 30])));
+        let mut bytes = Cursor::new(Vec::new());
+        image.write_to(&mut bytes, format).unwrap();
+        bytes.into_inner()
+    }
+
+    fn png_base64() -> String {
+        general_purpose::STANDARD.encode(encoded(ImageOutputFormat::Png))
+    }
+
+    #[test]
+    fn data_url_formats() {
+        assert_eq!(
+            data_url_format("data:image/png;base64"),
+            Some(ImageFormat::Png)
+        );
+        assert_eq!(data_url_format("image/png;base64"), Some(ImageFormat::Png));
+        assert_eq!(
+            data_url_format("data:IMAGE/PNG;base64"),
+            Some(ImageFormat::Png)
+        );
+        assert_eq!(
+            data_url_format("data:image/jpeg;base64"),
+            Some(ImageFormat::Jpeg)
+        );
+        assert_eq!(
+            data_url_format("data:image/jpg;base64"),
+            Some(ImageFormat::Jpeg)
+        );
+        assert_eq!(
+            data_url_format("data:image/pjpeg;base64"),
+            Some(ImageFormat::Jpeg)
+        );
+        assert_eq!(data_url_format("data:image/png"), Some(ImageFormat::Png));
+        assert_eq!(data_url_format("data:;base64"), None);
+        assert_eq!(
+            data_url_format("data:application/octet-stream;base64"),
+            None
+        );
+    }
+
+    #[test]
+    fn decodes_data_url_variants() {
+        let payload = png_base64();
+        for header in [
+            "data:image/png;base64",
+            "image/png;base64",
+            "data:image/png",
+            "data:application/octet-stream;base64",
+            "data:;base64",
+        ] {
+            let image = decode_base64(&format!("{header},{payload}"), WHITE).unwrap();
+            assert_eq!((image.width(), image.height()), (4, 3), "{header}");
+        }
+    }
+
+    #[test]
+    fn decodes_jpg_alias() {
+        let payload = general_purpose::STANDARD.encode(encoded(ImageOutputFormat::Jpeg(90)));
+        let image = decode_base64(&format!("data:image/jpg;base64,{payload}"), WHITE).unwrap();
+        assert_eq!((image.width(), image.height()), (4, 3));
+    }
+
+    #[test]
+    fn rejects_mismatched_data_url_type() {
+        let err =
+            decode_base64(&format!("data:image/jpeg;base64,{}", png_base64()), WHITE).unwrap_err();
+        assert!(err.to_string().contains("does not match"), "{err}");
+    }
+
+    #[test]
+    fn decodes_wrapped_unpadded_and_url_safe_base64() {
+        let bytes = encoded(ImageOutputFormat::Png);
+        let wrapped = png_base64()
+            .as_bytes()
+            .chunks(16)
+            .map(|line| std::str::from_utf8(line).unwrap())
+            .collect::<Vec<&str>>()
+            .join("\r\n");
+        let unpadded = png_base64().trim_end_matches('=').to_owned();
+        let url_safe = general_purpose::URL_SAFE_NO_PAD.encode(&bytes);
+        for payload in [wrapped, unpadded, url_safe] {
+            assert_eq!(decode_base64(&payload, WHITE).unwrap().width(), 4);
+        }
+        assert!(decode_base64("not base64!", WHITE).is_err());
+    }
+
+    #[test]
+    fn decodes_raw_bytes_and_base64_text() {
+        let bytes = encoded(ImageOutputFormat::Png);
+        assert_eq!(decode_bytes(&bytes, WHITE).unwrap().height(), 3);
+        assert_eq!(
+            decode_bytes(png_base64().as_bytes(), WHITE)
+                .unwrap()
+                .height(),
+            3
+        );
+    }
+
+    #[test]
+    fn flattens_transparency_onto_the_background() {
+        let mut rgba = RgbaImage::from_pixel(2, 1, Rgba([0, 0, 0, 0]));
+        rgba.put_pixel(1, 0, Rgba([200, 100, 0, 255]));
+        let flat = flatten(DynamicImage::ImageRgba8(rgba), Rgb([0, 128, 255]));
+        let flat = flat.as_rgb8().unwrap();
+        assert_eq!(flat.get_pixel(0, 0), &Rgb([0, 128, 255]));
+        assert_eq!(flat.get_pixel(1, 0), &Rgb([200, 100, 0]));
+    }
+
+    #[test]
+    fn parses_colors() {
+        assert_eq!(parse_color("#ff8000").unwrap(), Rgb([255, 128, 0]));
+        assert_eq!(parse_color("00Ff10").unwrap(), Rgb([0, 255, 16]));
+        assert!(parse_color("#fff").is_err());
+        assert!(parse_color("#gggggg").is_err());
+        assert!(parse_color("#ffé000").is_err());
+    }
+}
@@ -0,0 +1,382 @@
+//! Offline accuracy evaluation against a labeled corpus
+
+use anyhow::{Context, Result};
+use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+use serde::Serialize;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    model::{self, descriptor::ResizeFilter, ModelType, Predictor},
+    EvalArgs, ModelArgs,
+};
+
+/// Image file extensions of the corpus
+const IMAGE_EXTENSIONS: [&str; 3] = ["jpg", "jpeg", "png"];
+
+/// Corpus directories not named after their model type
+const CORPUS_ALIASES: [(&str, ModelType); 1] = [("penguins", ModelType::Penguin)];
+
+/// Report output format
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default)]
+pub enum EvalFormat {
+    /// Human readable tables
+    #[default]
+    Table,
+    /// Single JSON document
+    Json,
+}
+
+#[derive(Debug, Serialize)]
+struct Report {
+    images: usize,
+    correct: usize,
+    accuracy: f64,
+    models: Vec<ModelReport>,
+}
+
+#[derive(Debug, Serialize)]
+struct ModelReport {
+    #[serde(rename = "type")]
+    typed: &'static str,
+    /// resize filter of `--resize`, the model config one if not set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    resize: Option<ResizeFilter>,
+    images: usize,
+    correct: usize,
+    accuracy: f64,
+    /// rows are the labeled answers, columns the predicted ones
+    confusion: Vec<Vec<usize>>,
+    misclassified: Vec<Misclassified>,
+    /// files that could not be read, decoded or predicted
+    errors: Vec<FileError>,
+}
+
+#[derive(Debug, Serialize)]
+struct Misclassified {
+    file: PathBuf,
+    expected: u32,
+    predicted: i32,
+    confidence: f32,
+}
+
+#[derive(Debug, Serialize)]
+struct FileError {
+    file: PathBuf,
+    error: String,
+}
+
+/// Outcome of a single corpus image
+enum Outcome {
+    Predicted {
+        file: PathBuf,
+        expected: u32,
+        predicted: i32,
+        confidence: f32,
+    },
+    Failed(FileError),
+}
+
+/// Run the predictors over `<dir>/<type>/*.jpg` and compare with the `.txt` labels
+pub fn eval(args: EvalArgs) -> Result<()> {
+    let mut models = Vec::new();
+    for (model_type, dir) in corpus_models(&args.dir, &args.types)? {
+        if args.resize.is_empty() {
+            model::init_model(model_type, &args.model)?;
+            let predictor = model::get_predictor(model_type)?;
+            models.push(eval_model(model_type, &dir, predictor, None)?);
+            continue;
+        }
+        for &resize in &args.resize {
+            let predictor = model::new_predictor(
+                model_type,
+                &ModelArgs {
+                    resize: Some(resize),
+                    ..args.model.clone()
+                },
+            )?;
+            models.push(eval_model(
+                model_type,
+                &dir,
+                predictor.as_ref(),
+                Some(resize),
+            )?);
+        }
+    }
+
+    let images = models.iter().map(|model| model.images).sum();
+    let correct = models.iter().map(|model| model.correct).sum();
+    let report = Report {
+        images,
+        correct,
+        accuracy: ratio(correct, images),
+        models,
+    };
+
+    match args.format {
+        EvalFormat::Table => print_table(&report),
+        EvalFormat::Json => println!("{}", serde_json::to_string_pretty(&report)?),
+    }
+
+    if let Some(min_accuracy) = args.min_accuracy {
+        let failed = report
+            .models
+            .iter()
+            .filter(|model| model.accuracy < min_accuracy)
+            .map(|model| format!("{} ({:.2}%)", model.name(), model.accuracy * 100.0))
+            .collect::<Vec<String>>();
+        if !failed.is_empty() {
+            anyhow::bail!(
+                "accuracy below {:.2}%: {}",
+                min_accuracy * 100.0,
+                failed.join(", ")
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Evaluate every labeled image of a model directory
+fn eval_model(
+    model_type: ModelType,
+    dir: &Path,
+    predictor: &dyn Predictor,
+    resize: Option<ResizeFilter>,
+) -> Result<ModelReport> {
+    let files = corpus_images(dir)?;
+    let outcomes = files
+        .par_iter()
+        .map(|file| {
+            let predict = || -> Result<Outcome> {
+                let expected = label(file)?;
+                let image = image::open(file)?;
+                let prediction = predictor.predict(image)?;
+                Ok(Outcome::Predicted {
+                    file: file.clone(),
+                    expected,
+                    predicted: prediction.answer,
+                    confidence: prediction.confidence(),
+                })
+            };
+            predict().unwrap_or_else(|err| {
+                Outcome::Failed(FileError {
+                    file: file.clone(),
+                    error: err.to_string(),
+                })
+            })
+        })
+        .collect::<Vec<Outcome>>();
+
+    let mut report = ModelReport {
+        typed: model_type.as_str(),
+        resize,
+        images: 0,
+        correct: 0,
+        accuracy: 0.0,
+        confusion: Vec::new(),
+        misclassified: Vec::new(),
+        errors: Vec::new(),
+    };
+    for outcome in outcomes {
+        report.images += 1;
+        match outcome {
+            Outcome::Predicted {
+                file,
+                expected,
+                predicted,
+                confidence,
+            } => {
+                let (row, column) = (expected as usize, predicted.max(0) as usize);
+                let size = report.confusion.len().max(row + 1).max(column + 1);
+                report.confusion.resize_with(size, Vec::new);
+                for counts in report.confusion.iter_mut() {
+                    counts.resize(size, 0);
+                }
+                report.confusion[row][column] += 1;
+
+                if i64::from(expected) == i64::from(predicted) {
+                    report.correct += 1;
+                } else {
+                    report.misclassified.push(Misclassified {
+                        file,
+                        expected,
+                        predicted,
+                        confidence,
+                    });
+                }
+            }
+            Outcome::Failed(error) => report.errors.push(error),
+        }
+    }
+    report.accuracy = ratio(report.correct, report.images);
+    Ok(report)
+}
+
+/// Model type directories of the corpus, optionally restricted to the given types
+pub(crate) fn corpus_models(dir: &Path, types: &[ModelType]) -> Result<Vec<(ModelType, PathBuf)>> {
+    let mut dirs = fs::read_dir(dir)
+        .with_context(|| format!("failed to read {}", dir.display()))?
+        .map(|entry| Ok(entry?.path()))
+        .collect::<Result<Vec<PathBuf>>>()?;
+    dirs.sort();
+
+    let mut models = Vec::new();
+    for dir in dirs.into_iter().filter(|path| path.is_dir()) {
+        let name = dir
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let Some(model_type) = corpus_model(&name) else {
+            if types.is_empty() {
+                eprintln!("Skipping {}: not a model type", dir.display());
+            }
+            continue;
+        };
+        if types.is_empty() || types.contains(&model_type) {
+            models.push((model_type, dir));
+        }
+    }
+    Ok(models)
+}
+
+/// Model type of a corpus directory name
+fn corpus_model(name: &str) -> Option<ModelType> {
+    CORPUS_ALIASES
+        .iter()
+        .find(|(alias, _)| *alias == name)
+        .map(|&(_, model_type)| model_type)
+        .or_else(|| name.parse().ok())
+}
+
+/// Image files of a model directory, sorted by name
+pub(crate) fn corpus_images(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = fs::read_dir(dir)?
+        .map(|entry| Ok(entry?.path()))
+        .collect::<Result<Vec<PathBuf>>>()?;
+    files.retain(|path| {
+        path.extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| IMAGE_EXTENSIONS.contains(&ext.to_ascii_lowercase().as_str()))
+    });
+    files.sort();
+    Ok(files)
+}
+
+/// Labeled answer of an image: its `.txt` sidecar, or the `_<answer>` file name suffix
+pub(crate) fn label(file: &Path) -> Result<u32> {
+    if let Ok(text) = fs::read_to_string(file.with_extension("txt")) {
+        return Ok(text.trim().parse()?);
+    }
+    file.file_stem()
+        .and_then(|stem| stem.to_str())
+        .and_then(|stem| stem.rsplit('_').next())
+        .and_then(|answer| answer.parse().ok())
+        .ok_or_else(|| anyhow::anyhow!("missing label"))
+}
+
+pub(crate) fn ratio(correct: usize, total: usize) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        correct as f64 / total as f64
+    }
+}
+
+impl ModelReport {
+    /// Model type, with the resize filter when comparing filters
+    fn name(&self) -> String {
+        match self.resize {
+            Some(resize) => format!("{}/{}", self.typed, resize.as_str()),
+            None => self.typed.to_owned(),
+        }
+    }
+}
+
+fn print_table(report: &Report) {
+    println!(
+        "{:<24} {:>8} {:>8} {:>9}",
+        "MODEL", "IMAGES", "CORRECT", "ACCURACY"
+    );
+    for model in &report.models {
+        println!(
+            "{:<24} {:>8} {:>8} {:>8.2}%",
+            model.name(),
+            model.images,
+            model.correct,
+            model.accuracy * 100.0
+        );
+    }
+    println!(
+        "{:<24} {:>8} {:>8} {:>8.2}%",
+        "TOTAL",
+        report.images,
+        report.correct,
+        report.accuracy * 100.0
+    );
+
+    for model in &report.models {
+        println!();
+        println!(
+            "{} confusion (rows: expected, columns: predicted)",
+            model.name()
+        );
+        print!("{:>6}", "");
+        for column in 0..model.confusion.len() {
+            print!("{column:>5}");
+        }
+        println!();
+        for (row, counts) in model.confusion.iter().enumerate() {
+            print!("{row:>6}");
+            for count in counts {
+                print!("{count:>5}");
+            }
+            println!();
+        }
+        for miss in &model.misclassified {
+            println!(
+                "  {}: expected {}, predicted {} (confidence {:.3})",
+                miss.file.display(),
+                miss.expected,
+                miss.predicted,
+                miss.confidence
+            );
+        }
+        for error in &model.errors {
+            println!("  {}: {}", error.file.display(), error.error);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn corpus_directories() {
+        assert_eq!(corpus_model("penguins"), Some(ModelType::Penguin));
+        assert_eq!(corpus_model("penguin"), Some(ModelType::Penguin));
+        assert_eq!(corpus_model("shadows"), Some(ModelType::Shadows));
+        assert_eq!(corpus_model("frankenhead"), None);
+    }
+
+    #[test]
+    fn labels_from_sidecar_or_file_name() {
+        let dir = tempfile::tempdir().unwrap();
+        let named = dir.path().join("0a1b_4.jpg");
+        assert_eq!(label(&named).unwrap(), 4);
+
+        let sidecar = dir.path().join("0a1b_4_x.jpg");
+        fs::write(sidecar.with_extension("txt"), "2\n").unwrap();
+        assert_eq!(label(&sidecar).unwrap(), 2);
+
+        assert!(label(&dir.path().join("unlabeled.jpg")).is_err());
+    }
+
+    #[test]
+    fn ratios() {
+        assert_eq!(ratio(0, 0), 0.0);
+        assert_eq!(ratio(3, 4), 0.75);
+    }
+}
@@ -1,14 +1,26 @@
 pub mod alloc;
+pub mod bench;
+pub mod cache;
 #[cfg(target_family = "unix")]
 pub mod daemon;
+pub mod decode;
+pub mod eval;
 pub mod homedir;
+pub mod logging;
+pub mod metrics;
 pub mod model;
+pub mod models;
+pub mod predict;
 pub mod serve;
 pub mod update;
 
 use anyhow::Result;
 use clap::{Args, Parser, Subcommand};
+use eval::EvalFormat;
 pub use homedir::setting_dir;
+use logging::{LogFormat, LogRotation};
+use model::{descriptor::ResizeFilter, inference::Allocator, ModelType};
+use serve::AccessLogFormat;
 use std::{net::SocketAddr, path::PathBuf};
 
 #[derive(Parser)]
@@ -40,6 +52,167 @@ pub enum Commands {
     Log,
     /// Update the application
     Update,
+    /// Inspect or clear the persistent answer cache
+    Cache(CacheArgs),
+    /// Evaluate the models against a labeled image corpus
+    Eval(EvalArgs),
+    /// Benchmark the models latency and throughput
+    Bench(BenchArgs),
+    /// Predict images without starting the server
+    Predict(PredictArgs),
+    /// Compare and adopt quantized model variants
+    Models(ModelsArgs),
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct CacheArgs {
+    /// Funcaptcha model directory
+    #[clap(long, global = true)]
+    pub model_dir: Option<PathBuf>,
+
+    #[clap(subcommand)]
+    pub command: CacheCommand,
+}
+
+#[derive(Subcommand, Clone, Debug)]
+pub enum CacheCommand {
+    /// Show the number of entries, size and age of the cache
+    Info,
+    /// Remove every entry, the server must be stopped
+    Clear,
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct ModelsArgs {
+    #[clap(subcommand)]
+    pub command: ModelsCommand,
+}
+
+#[derive(Subcommand, Clone, Debug)]
+pub enum ModelsCommand {
+    /// Compare the accuracy and latency of the INT8 variants with the fp32 models
+    Compare(CompareArgs),
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct CompareArgs {
+    /// Labeled corpus directory, `<dir>/<type>/<name>_<answer>.jpg` with the answer in `.txt`
+    #[clap(long, default_value = "images")]
+    pub dir: PathBuf,
+
+    /// Only compare these model types, e.g. shadows,penguin
+    #[clap(long = "type", value_delimiter = ',')]
+    pub types: Vec<ModelType>,
+
+    /// Largest accuracy drop of an adopted variant, from 0 to 1
+    #[clap(long, default_value = "0.01")]
+    pub max_drop: f64,
+
+    /// Adopt the variants within --max-drop, and withdraw the others
+    #[clap(long)]
+    pub adopt: bool,
+
+    #[clap(flatten)]
+    pub model: ModelArgs,
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct EvalArgs {
+    /// Labeled corpus directory, `<dir>/<type>/<name>_<answer>.jpg` with the answer in `.txt`
+    #[clap(long, default_value = "images")]
+    pub dir: PathBuf,
+
+    /// Only evaluate these model types, e.g. shadows,penguin
+    #[clap(long = "type", value_delimiter = ',')]
+    pub types: Vec<ModelType>,
+
+    /// Report format
+    #[clap(long, value_enum, default_value_t = EvalFormat::Table)]
+    pub format: EvalFormat,
+
+    /// Exit with an error if any model is below this accuracy, from 0 to 1
+    #[clap(long)]
+    pub min_accuracy: Option<f64>,
+
+    /// Compare these resize filters, overriding the model config e.g. nearest,triangle,lanczos3
+    #[clap(long, value_enum, value_delimiter = ',')]
+    pub resize: Vec<ResizeFilter>,
+
+    #[clap(flatten)]
+    pub model: ModelArgs,
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct BenchArgs {
+    /// Image corpus directory, `<dir>/<type>/*.jpg`
+    #[clap(long, default_value = "images")]
+    pub dir: PathBuf,
+
+    /// Only benchmark these model types, e.g. shadows,penguin
+    #[clap(long = "type", value_delimiter = ',')]
+    pub types: Vec<ModelType>,
+
+    /// Concurrent callers to sweep
+    #[clap(long, value_delimiter = ',', default_value = "1,2,4")]
+    pub concurrency: Vec<usize>,
+
+    /// Number of threads (ONNX Runtime) to sweep
+    #[clap(long, value_delimiter = ',', default_value = "1")]
+    pub num_threads: Vec<u16>,
+
+    /// Execution provider allocators to sweep e.g. device,arena (ONNX Runtime)
+    #[clap(long, value_delimiter = ',', default_value = "device", value_parser = alloc_parser)]
+    pub allocator: Vec<Allocator>,
+
+    /// Predictions per run
+    #[clap(long, default_value = "200")]
+    pub requests: usize,
+
+    /// Unmeasured predictions after loading each model
+    #[clap(long, default_value = "10")]
+    pub warmup: usize,
+
+    /// Funcaptcha model update check
+    #[clap(short = 'U', long)]
+    pub update_check: bool,
+
+    /// Funcaptcha model directory
+    #[clap(long)]
+    pub model_dir: Option<PathBuf>,
+
+    /// Per-model descriptor overrides, a JSON file keyed by model file name
+    #[clap(long)]
+    pub model_config: Option<PathBuf>,
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct PredictArgs {
+    /// Model type, e.g. shadows
+    #[clap(long = "type")]
+    pub typed: ModelType,
+
+    /// Image files or base64 text files, `-` reads stdin
+    #[clap(required = true)]
+    pub inputs: Vec<String>,
+
+    /// Print one JSON object per image
+    #[clap(long)]
+    pub json: bool,
+
+    /// Rescale, trim and crop images that do not match the model layout
+    #[clap(long)]
+    pub normalize_input: bool,
+
+    /// Background transparent images are flattened onto, as #rrggbb
+    #[clap(long, default_value = "#ffffff", value_parser = decode::parse_color)]
+    pub background: image::Rgb<u8>,
+
+    /// Write an annotated image of the crops and tile scores to this directory
+    #[clap(long)]
+    pub debug_image: Option<PathBuf>,
+
+    #[clap(flatten)]
+    pub model: ModelArgs,
 }
 
 #[derive(Args, Clone, Debug)]
@@ -48,6 +221,26 @@ pub struct BootArgs {
     #[clap(short, long)]
     pub debug: bool,
 
+    /// Log format
+    #[clap(long, value_enum, default_value_t = LogFormat::Text)]
+    pub log_format: LogFormat,
+
+    /// Log directory, logs are written to stdout if not set
+    #[clap(long)]
+    pub log_dir: Option<PathBuf>,
+
+    /// Log file rotation period
+    #[clap(long, value_enum, default_value_t = LogRotation::Daily)]
+    pub log_rotation: LogRotation,
+
+    /// Maximum number of rotated log files to keep
+    #[clap(long, default_value = "7")]
+    pub log_max_files: usize,
+
+    /// OpenTelemetry collector endpoint e.g. http://localhost:4318 (requires the `otlp` feature)
+    #[clap(long)]
+    pub otlp_endpoint: Option<String>,
+
     /// Bind address
     #[clap(short, long, default_value = "0.0.0.0:8000")]
     pub bind: SocketAddr,
@@ -64,10 +257,81 @@ pub struct BootArgs {
     #[clap(short = 'A', long)]
     pub api_key: Option<String>,
 
+    /// Access log file, one line per task
+    #[clap(long)]
+    pub access_log: Option<PathBuf>,
+
+    /// Access log format
+    #[clap(long, value_enum, default_value_t = AccessLogFormat::Json)]
+    pub access_log_format: AccessLogFormat,
+
+    /// Rescale, trim and crop images that do not match the model layout
+    #[clap(long)]
+    pub normalize_input: bool,
+
+    /// Background transparent images are flattened onto, as #rrggbb
+    #[clap(long, default_value = "#ffffff", value_parser = decode::parse_color)]
+    pub background: image::Rgb<u8>,
+
     /// Multiple image submission limits
     #[clap(short = 'M', long, default_value = "3")]
     pub multi_image_limit: usize,
 
+    /// Inference threads decoding and predicting images, the available cores if 0
+    #[clap(long, default_value = "0")]
+    pub inference_threads: usize,
+
+    /// Tasks waiting for an inference thread before new ones are rejected
+    #[clap(long, default_value = "64")]
+    pub inference_queue: usize,
+
+    /// Answer cache size, disabled if 0
+    #[clap(long, default_value = "0")]
+    pub cache_size: usize,
+
+    /// Answer cache time to live in seconds
+    #[clap(long, default_value = "600")]
+    pub cache_ttl: u64,
+
+    /// Persist the answer cache under the model directory
+    #[clap(long)]
+    pub cache_persist: bool,
+
+    /// Persistent answer cache size
+    #[clap(long, default_value = "100000")]
+    pub cache_disk_size: usize,
+
+    /// Capture decoded images and predictions to this directory for retraining
+    #[clap(long)]
+    pub capture_dir: Option<PathBuf>,
+
+    /// Fraction of images to capture, from 0 to 1
+    #[clap(long, default_value = "1.0")]
+    pub capture_rate: f64,
+
+    /// Capture directory quota in MiB
+    #[clap(long, default_value = "1024")]
+    pub capture_quota: u64,
+
+    /// Only capture answers with a confidence at or below this value, from 0 to 1
+    #[clap(long)]
+    pub capture_max_confidence: Option<f32>,
+
+    #[clap(flatten)]
+    pub model: ModelArgs,
+}
+
+/// Model loading options, shared by the server and the offline commands
+#[derive(Args, Clone, Debug)]
+pub struct ModelArgs {
+    /// Reuse the score of a near-duplicate tile within this perceptual hash Hamming distance
+    #[clap(long)]
+    pub tile_cache_distance: Option<u32>,
+
+    /// Perceptual hash tile cache size per model
+    #[clap(long, default_value = "4096")]
+    pub tile_cache_size: usize,
+
     /// Funcaptcha model update check
     #[clap(short = 'U', long)]
     pub update_check: bool,
@@ -76,19 +340,44 @@ pub struct BootArgs {
     #[clap(long)]
     pub model_dir: Option<PathBuf>,
 
+    /// Per-model descriptor overrides, a JSON file keyed by model file name
+    #[clap(long)]
+    pub model_config: Option<PathBuf>,
+
     /// Number of threads (ONNX Runtime)
     #[clap(long, default_value = "1")]
     pub num_threads: u16,
 
     /// Execution provider allocator e.g. device, arena (ONNX Runtime)
     #[clap(long, default_value = "device", value_parser = alloc_parser)]
-    pub allocator: ort::AllocatorType,
+    pub allocator: Allocator,
+
+    /// Sessions per model, parallel predictions check out a free one
+    #[clap(long, default_value = "1")]
+    pub session_pool_size: usize,
+
+    /// Run a warm-up prediction through every session at startup, failing if any model
+    /// cannot run
+    #[clap(long)]
+    pub warmup: bool,
+
+    /// Load the quantized INT8 variants adopted by `fcsrv models compare --adopt`
+    #[clap(long)]
+    pub prefer_quantized: bool,
+
+    /// Load the quantized variants even if not adopted
+    #[clap(skip)]
+    pub force_quantized: bool,
+
+    /// Resize filter overriding the model config
+    #[clap(skip)]
+    pub resize: Option<ResizeFilter>,
 }
 
-fn alloc_parser(s: &str) -> anyhow::Result<ort::AllocatorType> {
+fn alloc_parser(s: &str) -> anyhow::Result<Allocator> {
     match s {
-        "device" => Ok(ort::AllocatorType::Device),
-        "arena" => Ok(ort::AllocatorType::Arena),
+        "device" => Ok(Allocator::Device),
+        "arena" => Ok(Allocator::Arena),
         _ => anyhow::bail!("Invalid allocator type"),
     }
 }
@@ -0,0 +1,125 @@
+//! Tracing subscriber setup
+
+#[cfg(feature = "otlp")]
+mod otlp;
+
+use anyhow::Result;
+use tracing_appender::{
+    non_blocking::WorkerGuard,
+    rolling::{RollingFileAppender, Rotation},
+};
+use tracing_subscriber::{
+    fmt::{format::FmtSpan, writer::BoxMakeWriter},
+    layer::SubscriberExt,
+    util::SubscriberInitExt,
+    Layer,
+};
+
+use crate::BootArgs;
+
+/// Log file name prefix inside the log directory
+const LOG_FILE_PREFIX: &str = "fcsrv";
+/// Log file name suffix inside the log directory
+const LOG_FILE_SUFFIX: &str = "log";
+
+/// Log output format
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default)]
+pub enum LogFormat {
+    /// Human readable lines
+    #[default]
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+/// Log file rotation period
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default)]
+pub enum LogRotation {
+    Minutely,
+    Hourly,
+    #[default]
+    Daily,
+    Never,
+}
+
+impl From<LogRotation> for Rotation {
+    fn from(rotation: LogRotation) -> Self {
+        match rotation {
+            LogRotation::Minutely => Rotation::MINUTELY,
+            LogRotation::Hourly => Rotation::HOURLY,
+            LogRotation::Daily => Rotation::DAILY,
+            LogRotation::Never => Rotation::NEVER,
+        }
+    }
+}
+
+/// Flushes the log file and exported spans when dropped
+pub struct LogGuard {
+    _file: Option<WorkerGuard>,
+    #[cfg(feature = "otlp")]
+    _otlp: Option<otlp::OtlpGuard>,
+}
+
+/// Init tracing, the returned guard must be kept alive until exit
+pub fn init(args: &BootArgs) -> Result<LogGuard> {
+    let (writer, guard) = match args.log_dir.as_ref() {
+        Some(log_dir) => {
+            let appender = RollingFileAppender::builder()
+                .rotation(args.log_rotation.into())
+                .filename_prefix(LOG_FILE_PREFIX)
+                .filename_suffix(LOG_FILE_SUFFIX)
+                .max_log_files(args.log_max_files)
+                .build(log_dir)?;
+            let (writer, guard) = tracing_appender::non_blocking(appender);
+            (BoxMakeWriter::new(writer), Some(guard))
+        }
+        None => (BoxMakeWriter::new(std::io::stdout), None),
+    };
+
+    // Close events carry the timings of the decode, preprocessing and inference spans
+    let span_events = if args.debug {
+        FmtSpan::CLOSE
+    } else {
+        FmtSpan::NONE
+    };
+
+    let fmt_layer = tracing_subscriber::fmt::layer()
+        .with_ansi(args.log_dir.is_none())
+        .with_span_events(span_events)
+        .with_writer(writer);
+    let fmt_layer = match args.log_format {
+        LogFormat::Text => fmt_layer.boxed(),
+        LogFormat::Json => fmt_layer.json().boxed(),
+    };
+
+    #[cfg_attr(not(feature = "otlp"), allow(unused_mut))]
+    let mut layers = vec![fmt_layer
+        .with_filter(
+            tracing_subscriber::EnvFilter::try_from_default_env()
+                .unwrap_or_else(|_| "RUST_LOG=info".into()),
+        )
+        .boxed()];
+
+    #[cfg(feature = "otlp")]
+    let otlp_guard = match args.otlp_endpoint.as_deref() {
+        Some(endpoint) => {
+            let (layer, guard) = otlp::layer(endpoint)?;
+            layers.push(layer);
+            Some(guard)
+        }
+        None => None,
+    };
+
+    tracing_subscriber::registry().with(layers).init();
+
+    #[cfg(not(feature = "otlp"))]
+    if args.otlp_endpoint.is_some() {
+        tracing::warn!("--otlp-endpoint is ignored, fcsrv was built without the `otlp` feature");
+    }
+
+    Ok(LogGuard {
+        _file: guard,
+        #[cfg(feature = "otlp")]
+        _otlp: otlp_guard,
+    })
+}
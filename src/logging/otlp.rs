@@ -0,0 +1,57 @@
+//! OpenTelemetry trace export over OTLP/HTTP
+
+use anyhow::Result;
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{runtime, trace, Resource};
+use tokio::runtime::Runtime;
+use tracing::Subscriber;
+use tracing_subscriber::{filter::EnvFilter, registry::LookupSpan, Layer};
+
+/// Spans exported to the collector, independent of the log level
+const OTLP_FILTER: &str = "fcsrv=debug,warp=info";
+
+/// Keeps the exporter runtime alive and flushes pending spans on drop
+pub struct OtlpGuard {
+    // The batch exporter runs on its own runtime, the server runtime does not exist yet
+    _runtime: Runtime,
+}
+
+impl Drop for OtlpGuard {
+    fn drop(&mut self) {
+        opentelemetry::global::shutdown_tracer_provider();
+    }
+}
+
+/// Create the OpenTelemetry layer exporting spans to the given collector endpoint
+pub fn layer<S>(endpoint: &str) -> Result<(Box<dyn Layer<S> + Send + Sync>, OtlpGuard)>
+where
+    S: Subscriber + for<'span> LookupSpan<'span> + Send + Sync,
+{
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(1)
+        .thread_name("otlp-exporter")
+        .enable_all()
+        .build()?;
+    let _enter = runtime.enter();
+
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .http()
+                .with_endpoint(endpoint),
+        )
+        .with_trace_config(trace::config().with_resource(Resource::new(vec![
+            KeyValue::new("service.name", env!("CARGO_PKG_NAME")),
+            KeyValue::new("service.version", env!("CARGO_PKG_VERSION")),
+        ])))
+        .install_batch(runtime::Tokio)?;
+
+    let layer = tracing_opentelemetry::layer()
+        .with_tracer(tracer)
+        .with_filter(EnvFilter::new(OTLP_FILTER))
+        .boxed();
+
+    Ok((layer, OtlpGuard { _runtime: runtime }))
+}
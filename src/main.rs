@@ -1,6 +1,6 @@
 use anyhow::Result;
 use clap::Parser;
-use fcsrv::{daemon, update, Commands, Opt};
+use fcsrv::{bench, cache, daemon, eval, models, predict, update, Commands, Opt};
 
 fn main() -> crate::Result<()> {
     let opt = Opt::parse();
@@ -18,6 +18,11 @@ fn main() -> crate::Result<()> {
         #[cfg(target_family = "unix")]
         Commands::Log => daemon::log()?,
         Commands::Update => update::update()?,
+        Commands::Cache(args) => cache::cache(args)?,
+        Commands::Eval(args) => eval::eval(args)?,
+        Commands::Bench(args) => bench::bench(args)?,
+        Commands::Predict(args) => predict::predict(args)?,
+        Commands::Models(args) => models::models(args)?,
     };
 
     Ok(())
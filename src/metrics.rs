@@ -0,0 +1,230 @@
+//! Prometheus metrics
+
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+/// Server metrics, rendered by `GET /metrics`
+pub static METRICS: Metrics = Metrics::new();
+
+pub struct Metrics {
+    /// answer cache hits
+    pub cache_hits: AtomicU64,
+    /// answer cache misses
+    pub cache_misses: AtomicU64,
+    /// perceptual hash tile cache hits
+    pub tile_cache_hits: AtomicU64,
+    /// perceptual hash tile cache misses
+    pub tile_cache_misses: AtomicU64,
+    /// tasks rejected because the inference queue was full
+    pub inference_rejected: AtomicU64,
+    /// queued tasks dropped because the client disconnected
+    pub inference_cancelled: AtomicU64,
+    /// time tasks waited for an inference thread
+    pub queue_wait: Histogram,
+    /// feedback answers per model type name
+    feedback: Mutex<BTreeMap<&'static str, Accuracy>>,
+}
+
+/// Upper bounds of the queue wait buckets, in seconds
+const QUEUE_WAIT_BUCKETS: [f64; 10] = [0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 5.0];
+
+/// Duration histogram with the fixed queue wait buckets
+pub struct Histogram {
+    /// observations per bucket, not cumulative, the last one is `+Inf`
+    buckets: [AtomicU64; QUEUE_WAIT_BUCKETS.len() + 1],
+    /// sum of the observations in microseconds
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    const fn new() -> Self {
+        // repeated by copy of the constant, inline const blocks need Rust 1.79
+        #[allow(clippy::declare_interior_mutable_const)]
+        const ZERO: AtomicU64 = AtomicU64::new(0);
+        Self {
+            buckets: [ZERO; QUEUE_WAIT_BUCKETS.len() + 1],
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        let bucket = QUEUE_WAIT_BUCKETS
+            .iter()
+            .position(|&bound| seconds <= bound)
+            .unwrap_or(QUEUE_WAIT_BUCKETS.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        header(out, name, help, "histogram");
+        let mut count = 0;
+        for (i, bucket) in self.buckets.iter().enumerate() {
+            count += bucket.load(Ordering::Relaxed);
+            match QUEUE_WAIT_BUCKETS.get(i) {
+                Some(bound) => {
+                    let _ = writeln!(out, "{name}_bucket{{le=\"{bound}\"}} {count}");
+                }
+                None => {
+                    let _ = writeln!(out, "{name}_bucket{{le=\"+Inf\"}} {count}");
+                }
+            }
+        }
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1e6;
+        let _ = writeln!(out, "{name}_sum {sum}");
+        let _ = writeln!(out, "{name}_count {count}");
+    }
+}
+
+/// Feedback counters of a model
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct Accuracy {
+    /// answers reported through feedback
+    pub total: u64,
+    /// reported answers that matched the prediction
+    pub correct: u64,
+}
+
+impl Accuracy {
+    /// Ratio of correct answers, `None` without feedback
+    pub fn ratio(&self) -> Option<f64> {
+        (self.total > 0).then(|| self.correct as f64 / self.total as f64)
+    }
+}
+
+impl Metrics {
+    const fn new() -> Self {
+        Self {
+            cache_hits: AtomicU64::new(0),
+            cache_misses: AtomicU64::new(0),
+            tile_cache_hits: AtomicU64::new(0),
+            tile_cache_misses: AtomicU64::new(0),
+            inference_rejected: AtomicU64::new(0),
+            inference_cancelled: AtomicU64::new(0),
+            queue_wait: Histogram::new(),
+            feedback: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Count feedback answers of the model
+    pub fn record_feedback(&self, model: &'static str, total: u64, correct: u64) {
+        let mut feedback = self.feedback.lock().unwrap_or_else(|e| e.into_inner());
+        let accuracy = feedback.entry(model).or_default();
+        accuracy.total += total;
+        accuracy.correct += correct;
+    }
+
+    /// Feedback counters of every model that received feedback
+    pub fn accuracy(&self) -> BTreeMap<&'static str, Accuracy> {
+        self.feedback
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// Render the metrics in the Prometheus text format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        counter(
+            &mut out,
+            "fcsrv_cache_hits_total",
+            "Answer cache hits",
+            &self.cache_hits,
+        );
+        counter(
+            &mut out,
+            "fcsrv_cache_misses_total",
+            "Answer cache misses",
+            &self.cache_misses,
+        );
+        counter(
+            &mut out,
+            "fcsrv_tile_cache_hits_total",
+            "Perceptual hash tile cache hits",
+            &self.tile_cache_hits,
+        );
+        counter(
+            &mut out,
+            "fcsrv_tile_cache_misses_total",
+            "Perceptual hash tile cache misses",
+            &self.tile_cache_misses,
+        );
+        counter(
+            &mut out,
+            "fcsrv_inference_rejected_total",
+            "Tasks rejected because the inference queue was full",
+            &self.inference_rejected,
+        );
+        counter(
+            &mut out,
+            "fcsrv_inference_cancelled_total",
+            "Queued tasks dropped because the client disconnected",
+            &self.inference_cancelled,
+        );
+        self.queue_wait.render(
+            &mut out,
+            "fcsrv_inference_queue_wait_seconds",
+            "Time tasks waited for an inference thread",
+        );
+
+        let accuracy = self.accuracy();
+        header(
+            &mut out,
+            "fcsrv_feedback_total",
+            "Answers reported through feedback",
+            "counter",
+        );
+        for (model, accuracy) in &accuracy {
+            let _ = writeln!(
+                out,
+                "fcsrv_feedback_total{{model=\"{model}\"}} {}",
+                accuracy.total
+            );
+        }
+        header(
+            &mut out,
+            "fcsrv_feedback_correct_total",
+            "Reported answers that matched the prediction",
+            "counter",
+        );
+        for (model, accuracy) in &accuracy {
+            let _ = writeln!(
+                out,
+                "fcsrv_feedback_correct_total{{model=\"{model}\"}} {}",
+                accuracy.correct
+            );
+        }
+        header(
+            &mut out,
+            "fcsrv_model_accuracy",
+            "Ratio of correct answers reported through feedback",
+            "gauge",
+        );
+        for (model, accuracy) in &accuracy {
+            if let Some(ratio) = accuracy.ratio() {
+                let _ = writeln!(out, "fcsrv_model_accuracy{{model=\"{model}\"}} {ratio}");
+            }
+        }
+        out
+    }
+}
+
+fn counter(out: &mut String, name: &str, help: &str, value: &AtomicU64) {
+    header(out, name, help, "counter");
+    let _ = writeln!(out, "{name} {}", value.load(Ordering::Relaxed));
+}
+
+fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
@@ -1,7 +1,6 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 use image::DynamicImage;
-use ndarray::Array4;
-use ort::{GraphOptimizationLevel, Session};
+use ndarray::{s, Array4, Axis};
 use sha2::Digest;
 use sha2::Sha256;
 use std::{
@@ -11,43 +10,91 @@ use std::{
     path::{Path, PathBuf},
 };
 
-use crate::homedir;
-use crate::BootArgs;
+use crate::cache::phash::{self, TileCache};
+use crate::ModelArgs;
 
-use super::image_processing::check_input_image_size;
-use super::image_processing::process_classifier_image;
-use super::image_processing::process_pair_classifier_ans_image;
-use super::image_processing::process_pair_classifier_image;
+use super::descriptor::{Descriptor, TensorLayout};
+use super::image_processing::{nchw, process_image, rgb8};
+use super::inference::{self, Inference, SessionPool};
+use super::quantized;
+use super::{Layout, Prediction};
 
-pub struct ImagePairClassifierPredictor(Session);
-pub struct ImageClassifierPredictor(Session);
+pub struct ImagePairClassifierPredictor {
+    session: SessionPool,
+    descriptor: Descriptor,
+    /// name of the score output
+    output: String,
+    /// whether the inputs take several tiles per run
+    batch: bool,
+    tile_cache: Option<TileCache>,
+}
+
+pub struct ImageClassifierPredictor {
+    session: SessionPool,
+    descriptor: Descriptor,
+    /// name of the score output
+    output: String,
+    /// whether the inputs take several tiles per run
+    batch: bool,
+    tile_cache: Option<TileCache>,
+}
 
 impl ImagePairClassifierPredictor {
     /// Create a new instance of the ImagePairClassifierPredictor
-    pub fn new(onnx: &'static str, args: &BootArgs) -> Result<Self> {
-        Ok(Self(create_model_session(onnx, args)?))
+    pub fn new(onnx: &'static str, args: &ModelArgs) -> Result<Self> {
+        let descriptor = load_descriptor(Descriptor::pair_classifier(), onnx, args)?;
+        descriptor.check(true)?;
+        let session = create_session_pool(onnx, args, &descriptor)?;
+        let tensors = &descriptor.tensors;
+        let (output, batch) = check_signature(
+            session.first(),
+            &descriptor,
+            &[&tensors.input_left, &tensors.input_right],
+        )
+        .with_context(|| format!("model {onnx} does not match its descriptor"))?;
+        Ok(Self {
+            session,
+            descriptor,
+            output,
+            batch,
+            tile_cache: create_tile_cache(args),
+        })
     }
 }
 
 impl ImageClassifierPredictor {
     /// Create a new instance of the ImageClassifierPredictor
-    pub fn new(onnx: &'static str, args: &BootArgs) -> Result<Self> {
-        Ok(Self(create_model_session(onnx, args)?))
+    pub fn new(onnx: &'static str, args: &ModelArgs) -> Result<Self> {
+        let descriptor = load_descriptor(Descriptor::classifier(), onnx, args)?;
+        descriptor.check(false)?;
+        let session = create_session_pool(onnx, args, &descriptor)?;
+        let (output, batch) =
+            check_signature(session.first(), &descriptor, &[&descriptor.tensors.input])
+                .with_context(|| format!("model {onnx} does not match its descriptor"))?;
+        Ok(Self {
+            session,
+            descriptor,
+            output,
+            batch,
+            tile_cache: create_tile_cache(args),
+        })
     }
 }
 
 impl ImagePairClassifierPredictor {
     /// Run prediction on the model
+    #[tracing::instrument(level = "debug", skip_all)]
     pub fn run_prediction(&self, left: Array4<f32>, right: Array4<f32>) -> Result<Vec<f32>> {
-        let inputs = ort::inputs! {
-            "input_left" => left,
-            "input_right" => right,
-        }?;
-
-        let outputs = self.0.run(inputs)?;
-        let output = outputs[0]
-            .extract_tensor::<f32>()?
-            .view()
+        let tensors = &self.descriptor.tensors;
+        let inputs = vec![
+            (tensors.input_left.as_str(), left),
+            (tensors.input_right.as_str(), right),
+        ];
+
+        let output = self
+            .session
+            .checkout()
+            .run(inputs, &self.output)?
             .t()
             .into_owned()
             .into_iter()
@@ -56,35 +103,75 @@ impl ImagePairClassifierPredictor {
     }
 
     #[inline]
-    pub fn predict(&self, mut image: DynamicImage) -> Result<i32> {
-        check_input_image_size(&image)?;
-
-        let mut max_prediction = f32::NEG_INFINITY;
-        let width = image.width();
-        let mut max_index = 0;
-        let left = process_pair_classifier_ans_image(&mut image, (52, 52))?;
-
-        for i in 0..(width / 200) {
-            let right = process_pair_classifier_image(&image, (0, i), (52, 52))?;
-            let prediction = self.run_prediction(left.clone(), right)?;
-            let prediction_value = prediction[0];
-            if prediction_value > max_prediction {
-                max_prediction = prediction_value;
-                max_index = i;
-            }
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub fn predict(&self, image: DynamicImage) -> Result<Prediction> {
+        let layout = self.layout(&image)?;
+        let answer = layout
+            .answer
+            .ok_or_else(|| anyhow::anyhow!("pair classifier geometry has no answer region"))?;
+        let rgb = rgb8(&image);
+        let preprocess = &self.descriptor.preprocess;
+        let left = process_image(&rgb, &[answer], layout.input_shape, preprocess);
+        let left_hash = match self.tile_cache {
+            Some(_) => phash::dhash(nchw(left.view(), preprocess.layout)),
+            None => 0,
+        };
+
+        let tiles = process_image(&rgb, &layout.tiles, layout.input_shape, preprocess);
+        let cache = self.tile_cache.as_ref().map(|cache| (cache, left_hash));
+        let scores = tile_scores(&tiles, preprocess.layout, cache, self.batch, |right| {
+            // the answer image is repeated for every tile of the batch
+            let left = left.broadcast(right.raw_dim()).context("tile shape")?;
+            self.run_prediction(left.to_owned(), right)
+        })?;
+
+        let prediction = Prediction::from_scores(scores);
+        tracing::debug!(answer = prediction.answer, scores = ?prediction.scores, "prediction");
+        Ok(prediction)
+    }
+
+    /// Run the answer image and the first tile through every session, bypassing the
+    /// tile cache
+    pub fn warm_up(&self, image: &DynamicImage) -> Result<()> {
+        let layout = self.layout(image)?;
+        let answer = layout
+            .answer
+            .ok_or_else(|| anyhow::anyhow!("pair classifier geometry has no answer region"))?;
+        let rgb = rgb8(image);
+        let preprocess = &self.descriptor.preprocess;
+        let left = process_image(&rgb, &[answer], layout.input_shape, preprocess);
+        let right = process_image(&rgb, &layout.tiles[..1], layout.input_shape, preprocess);
+        let tensors = &self.descriptor.tensors;
+        for session in self.session.sessions() {
+            let inputs = vec![
+                (tensors.input_left.as_str(), left.clone()),
+                (tensors.input_right.as_str(), right.clone()),
+            ];
+            session.run(inputs, &self.output)?;
         }
-        Ok(max_index as i32)
+        Ok(())
+    }
+
+    /// Crop rectangles of the answer image and the tiles
+    pub fn layout(&self, image: &DynamicImage) -> Result<Layout> {
+        self.descriptor
+            .geometry
+            .layout(image.width(), image.height())
+    }
+
+    pub fn descriptor(&self) -> &Descriptor {
+        &self.descriptor
     }
 }
 
 impl ImageClassifierPredictor {
+    #[tracing::instrument(level = "debug", skip_all)]
     fn run_prediction(&self, image: Array4<f32>) -> Result<Vec<f32>> {
-        let outputs = self.0.run(ort::inputs! {
-            "input" => image,
-        }?)?;
-        let output = outputs[0]
-            .extract_tensor::<f32>()?
-            .view()
+        let inputs = vec![(self.descriptor.tensors.input.as_str(), image)];
+        let output = self
+            .session
+            .checkout()
+            .run(inputs, &self.output)?
             .t()
             .into_owned()
             .into_iter()
@@ -93,46 +180,236 @@ impl ImageClassifierPredictor {
     }
 
     #[inline]
-    pub fn predict(&self, mut image: DynamicImage) -> Result<i32> {
-        let mut max_prediction = f32::NEG_INFINITY;
-        let mut max_index: i32 = -1;
-
-        for i in 0..6 {
-            let ts = process_classifier_image(&mut image, i, (52, 52))?;
-
-            let prediction = self.run_prediction(ts)?;
-            let prediction_value = prediction[0];
-            if prediction_value > max_prediction {
-                max_prediction = prediction_value;
-                max_index = i as i32;
-            }
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub fn predict(&self, image: DynamicImage) -> Result<Prediction> {
+        let layout = self.layout(&image)?;
+        let tiles = process_image(
+            &rgb8(&image),
+            &layout.tiles,
+            layout.input_shape,
+            &self.descriptor.preprocess,
+        );
+        let preprocess = &self.descriptor.preprocess;
+        let cache = self.tile_cache.as_ref().map(|cache| (cache, 0));
+        let scores = tile_scores(&tiles, preprocess.layout, cache, self.batch, |tiles| {
+            self.run_prediction(tiles)
+        })?;
+
+        let prediction = Prediction::from_scores(scores);
+        tracing::debug!(answer = prediction.answer, scores = ?prediction.scores, "prediction");
+        Ok(prediction)
+    }
+
+    /// Run the first tile through every session, bypassing the tile cache
+    pub fn warm_up(&self, image: &DynamicImage) -> Result<()> {
+        let layout = self.layout(image)?;
+        let tile = process_image(
+            &rgb8(image),
+            &layout.tiles[..1],
+            layout.input_shape,
+            &self.descriptor.preprocess,
+        );
+        for session in self.session.sessions() {
+            let inputs = vec![(self.descriptor.tensors.input.as_str(), tile.clone())];
+            session.run(inputs, &self.output)?;
         }
+        Ok(())
+    }
 
-        Ok(max_index)
+    /// Crop rectangles of the tiles
+    pub fn layout(&self, image: &DynamicImage) -> Result<Layout> {
+        self.descriptor
+            .geometry
+            .layout(image.width(), image.height())
+    }
+
+    pub fn descriptor(&self) -> &Descriptor {
+        &self.descriptor
     }
 }
 
-fn create_model_session(onnx: &'static str, args: &BootArgs) -> Result<Session> {
-    let model_dir = args
-        .model_dir
-        .as_ref()
-        .map(|x| x.to_owned())
-        .unwrap_or_else(|| {
-            homedir::home_dir()
-                .unwrap_or(PathBuf::new())
-                .join(".funcaptcha_models")
-        });
+/// Apply the model config and the command line overrides to the built-in descriptor
+fn load_descriptor(descriptor: Descriptor, onnx: &str, args: &ModelArgs) -> Result<Descriptor> {
+    let mut descriptor = descriptor.load(onnx, args.model_config.as_deref())?;
+    if let Some(resize) = args.resize {
+        descriptor.preprocess.resize = resize;
+    }
+    Ok(descriptor)
+}
 
-    let model_file = initialize_model(onnx, model_dir, args.update_check)?;
-    let session = Session::builder()?
-        .with_optimization_level(GraphOptimizationLevel::Level3)?
-        .with_parallel_execution(false)?
-        .with_intra_threads(args.num_threads as i16)?
-        .with_allocator(args.allocator)?
-        .with_model_from_file(model_file)?;
-    Ok(session)
+fn create_tile_cache(args: &ModelArgs) -> Option<TileCache> {
+    TileCache::new(args.tile_cache_size, args.tile_cache_distance?)
 }
 
+/// Scores of the tiles, in a single run of the uncached tiles when the model takes
+/// batches. The scores of near duplicates, keyed by the context hash and the tile hash,
+/// are reused.
+fn tile_scores(
+    tiles: &Array4<f32>,
+    layout: TensorLayout,
+    cache: Option<(&TileCache, u64)>,
+    batch: bool,
+    run: impl Fn(Array4<f32>) -> Result<Vec<f32>>,
+) -> Result<Vec<f32>> {
+    let count = tiles.len_of(Axis(0));
+    let mut scores = vec![None; count];
+    let mut hashes = vec![0; count];
+    if let Some((cache, context)) = cache {
+        for (i, score) in scores.iter_mut().enumerate() {
+            hashes[i] = phash::dhash(nchw(tiles.slice(s![i..=i, .., .., ..]), layout));
+            *score = cache.get(context, hashes[i]);
+        }
+    }
+
+    let missing = (0..count)
+        .filter(|&i| scores[i].is_none())
+        .collect::<Vec<usize>>();
+    let computed = if batch && !missing.is_empty() {
+        let _tiles = tracing::debug_span!("tiles", count = missing.len()).entered();
+        run(tiles.select(Axis(0), &missing))?
+    } else {
+        let mut computed = Vec::with_capacity(missing.len());
+        for &i in &missing {
+            let _tile = tracing::debug_span!("tile", index = i).entered();
+            computed.extend(run(tiles.select(Axis(0), &[i]))?);
+        }
+        computed
+    };
+    if computed.len() != missing.len() {
+        anyhow::bail!(
+            "expected {} scores, the model returned {}",
+            missing.len(),
+            computed.len()
+        );
+    }
+
+    for (i, score) in missing.into_iter().zip(computed) {
+        if let Some((cache, context)) = cache {
+            cache.insert(context, hashes[i], score);
+        }
+        scores[i] = Some(score);
+    }
+    Ok(scores.into_iter().flatten().collect())
+}
+
+/// Check the session inputs and score output against the descriptor, returns the output
+/// name and whether the inputs have a dynamic batch dimension
+fn check_signature(
+    session: &dyn Inference,
+    descriptor: &Descriptor,
+    inputs: &[&str],
+) -> Result<(String, bool)> {
+    let names = |names: Vec<&str>| names.join(", ");
+    let geometry = &descriptor.geometry;
+    let expected = descriptor
+        .preprocess
+        .shape(1, (geometry.input_width, geometry.input_height));
+    let mut batch = true;
+    for &name in inputs {
+        let Some(input) = session.inputs().iter().find(|input| input.name == name) else {
+            let model_inputs = session.inputs().iter().map(|input| input.name.as_str());
+            anyhow::bail!(
+                "input {name:?} not found, the model has inputs {}",
+                names(model_inputs.collect())
+            );
+        };
+        let dimensions = input
+            .dimensions
+            .as_deref()
+            .with_context(|| format!("input {name:?} is not a float tensor"))?;
+        // dynamic dimensions are negative
+        let matches = dimensions.len() == expected.len()
+            && dimensions
+                .iter()
+                .zip(expected)
+                .all(|(&actual, expected)| actual < 0 || actual as usize == expected);
+        if !matches {
+            anyhow::bail!(
+                "input {name:?} has shape {}, expected {expected:?}",
+                shape(dimensions)
+            );
+        }
+        batch &= dimensions[0] < 0;
+    }
+
+    for input in session.inputs() {
+        if !inputs.contains(&input.name.as_str()) {
+            anyhow::bail!(
+                "unexpected input {:?}, expected inputs {}",
+                input.name,
+                names(inputs.to_vec())
+            );
+        }
+    }
+
+    let output = match descriptor.tensors.output.as_deref() {
+        Some(name) => session
+            .outputs()
+            .iter()
+            .find(|output| output.name == name)
+            .with_context(|| {
+                let outputs = session.outputs().iter().map(|output| output.name.as_str());
+                format!(
+                    "output {name:?} not found, the model has outputs {}",
+                    names(outputs.collect())
+                )
+            })?,
+        None => session
+            .outputs()
+            .first()
+            .context("the model has no outputs")?,
+    };
+    let dimensions = output
+        .dimensions
+        .as_deref()
+        .with_context(|| format!("output {:?} is not a float tensor", output.name))?;
+    if dimensions.iter().any(|&dimension| dimension > 1) {
+        anyhow::bail!(
+            "output {:?} has shape {}, expected a single score per tile",
+            output.name,
+            shape(dimensions)
+        );
+    }
+    Ok((output.name.clone(), batch))
+}
+
+/// `[1, 3, 52, 52]`, with `?` for dynamic dimensions
+fn shape(dimensions: &[i64]) -> String {
+    let dimensions = dimensions
+        .iter()
+        .map(|&dimension| match dimension {
+            d if d < 0 => "?".to_owned(),
+            d => d.to_string(),
+        })
+        .collect::<Vec<String>>();
+    format!("[{}]", dimensions.join(", "))
+}
+
+fn create_session_pool(
+    onnx: &'static str,
+    args: &ModelArgs,
+    descriptor: &Descriptor,
+) -> Result<SessionPool> {
+    let model_dir = super::model_dir(args.model_dir.as_deref());
+
+    let model_file = initialize_model(onnx, model_dir.clone(), args.update_check)?;
+    let model_file = match quantized::select(onnx, &model_dir, args)? {
+        Some(variant) => variant,
+        None => PathBuf::from(model_file),
+    };
+    let size = descriptor
+        .session
+        .pool_size
+        .unwrap_or(args.session_pool_size)
+        .max(1);
+    let sessions = (0..size)
+        .map(|_| inference::load(&model_file, args, &descriptor.session))
+        .collect::<Result<Vec<Box<dyn Inference>>>>()?;
+    tracing::debug!(model = onnx, size, "session pool");
+    Ok(SessionPool::new(sessions))
+}
+
+#[tracing::instrument(level = "debug", skip(model_dir), fields(model = model_name))]
 fn initialize_model(
     model_name: &'static str,
     model_dir: PathBuf,
@@ -194,6 +471,7 @@ fn initialize_model(
     Ok(model_filename)
 }
 
+#[tracing::instrument(level = "debug")]
 fn download_file(url: &str, filename: &str) -> Result<()> {
     let mut response = reqwest::blocking::get(url)?;
     let mut out = fs::File::create(filename)?;
@@ -222,3 +500,72 @@ fn file_sha256(filename: &str) -> Result<String> {
     }
     Ok(format!("{:x}", sha256.finalize()))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    /// Tiles of distinct patterns
+    fn tiles(count: usize) -> Array4<f32> {
+        Array4::from_shape_fn((count, 3, 8, 8), |(i, _, y, x)| {
+            ((x * (i + 1) + y * 7 * i) % 5) as f32
+        })
+    }
+
+    #[test]
+    fn scores_every_tile_in_one_batch() {
+        let runs = RefCell::new(Vec::new());
+        let scores = tile_scores(&tiles(4), TensorLayout::Nchw, None, true, |batch| {
+            runs.borrow_mut().push(batch.len_of(Axis(0)));
+            Ok(batch.outer_iter().map(|tile| tile.sum()).collect())
+        })
+        .unwrap();
+        assert_eq!(*runs.borrow(), vec![4]);
+        let expected = tiles(4)
+            .outer_iter()
+            .map(|tile| tile.sum())
+            .collect::<Vec<f32>>();
+        assert_eq!(scores, expected);
+    }
+
+    #[test]
+    fn scores_tiles_one_by_one_without_batches() {
+        let runs = RefCell::new(0);
+        let scores = tile_scores(&tiles(3), TensorLayout::Nchw, None, false, |batch| {
+            *runs.borrow_mut() += 1;
+            Ok(vec![batch.sum()])
+        })
+        .unwrap();
+        assert_eq!(*runs.borrow(), 3);
+        assert_eq!(scores.len(), 3);
+    }
+
+    #[test]
+    fn runs_only_the_uncached_tiles() {
+        let cache = TileCache::new(16, 0).unwrap();
+        let tiles = tiles(3);
+        let first = tile_scores(
+            &tiles,
+            TensorLayout::Nchw,
+            Some((&cache, 7)),
+            true,
+            |batch| Ok(batch.outer_iter().map(|tile| tile.sum()).collect()),
+        )
+        .unwrap();
+
+        // the cached scores come back, the model is not run
+        let second = tile_scores(&tiles, TensorLayout::Nchw, Some((&cache, 7)), true, |_| {
+            panic!("cached tiles were run")
+        })
+        .unwrap();
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn rejects_a_short_output() {
+        let err =
+            tile_scores(&tiles(2), TensorLayout::Nchw, None, true, |_| Ok(vec![1.0])).unwrap_err();
+        assert_eq!(err.to_string(), "expected 2 scores, the model returned 1");
+    }
+}
@@ -0,0 +1,177 @@
+//! Annotated debug image of the preprocessor crops and tile scores
+
+use anyhow::Result;
+use image::{imageops, DynamicImage, ImageOutputFormat, Rgb, RgbImage};
+use std::io::Cursor;
+
+use super::{descriptor::ResizeFilter, Layout, Prediction, Rect};
+
+/// Space around the image, the model inputs and the score bars
+const MARGIN: u32 = 8;
+/// Height of a full score bar
+const BAR_HEIGHT: u32 = 40;
+const BACKGROUND: Rgb<u8> = Rgb([255, 255, 255]);
+const BAR_BACKGROUND: Rgb<u8> = Rgb([220, 220, 220]);
+const ANSWER_COLOR: Rgb<u8> = Rgb([0, 120, 255]);
+const TILE_COLOR: Rgb<u8> = Rgb([0, 190, 0]);
+const CHOSEN_COLOR: Rgb<u8> = Rgb([230, 0, 0]);
+
+/// Render the image with its crop rectangles, above the resized model inputs with a
+/// softmax score bar under each tile. The answer image is blue and the chosen tile red.
+pub fn render(
+    image: &DynamicImage,
+    layout: &Layout,
+    resize: ResizeFilter,
+    prediction: &Prediction,
+) -> RgbImage {
+    let source = image.to_rgb8();
+    let (input_width, input_height) = layout.input_shape;
+    let inputs = layout.answer.iter().count() as u32 + layout.tiles.len() as u32;
+    let width = (source.width() + 2 * MARGIN).max(inputs * (input_width + MARGIN) + MARGIN);
+    let height = source.height() + input_height + BAR_HEIGHT + 4 * MARGIN;
+
+    let mut canvas = RgbImage::from_pixel(width, height, BACKGROUND);
+    imageops::replace(&mut canvas, &source, MARGIN as i64, MARGIN as i64);
+
+    // Crop rectangles, the chosen tile last so it is drawn on top
+    let chosen = usize::try_from(prediction.answer).ok();
+    let shift = |rect: Rect| Rect {
+        x: rect.x + MARGIN,
+        y: rect.y + MARGIN,
+        ..rect
+    };
+    if let Some(answer) = layout.answer {
+        draw_rect(&mut canvas, shift(answer), ANSWER_COLOR, 2);
+    }
+    for (i, &tile) in layout.tiles.iter().enumerate() {
+        if Some(i) != chosen {
+            draw_rect(&mut canvas, shift(tile), TILE_COLOR, 1);
+        }
+    }
+    if let Some(&tile) = chosen.and_then(|i| layout.tiles.get(i)) {
+        draw_rect(&mut canvas, shift(tile), CHOSEN_COLOR, 3);
+    }
+
+    // Resized model inputs, with the score bars under the tiles
+    let top = source.height() + 2 * MARGIN;
+    let bar_top = top + input_height + MARGIN;
+    let probabilities = prediction.probabilities();
+    let mut x = MARGIN;
+    if let Some(answer) = layout.answer {
+        draw_input(
+            &mut canvas,
+            &source,
+            answer,
+            layout.input_shape,
+            resize,
+            (x, top),
+            ANSWER_COLOR,
+        );
+        x += input_width + MARGIN;
+    }
+    for (i, &tile) in layout.tiles.iter().enumerate() {
+        let color = if Some(i) == chosen {
+            CHOSEN_COLOR
+        } else {
+            TILE_COLOR
+        };
+        draw_input(
+            &mut canvas,
+            &source,
+            tile,
+            layout.input_shape,
+            resize,
+            (x, top),
+            color,
+        );
+
+        let probability = probabilities.get(i).copied().unwrap_or_default();
+        let bar = ((probability * BAR_HEIGHT as f32).round() as u32).min(BAR_HEIGHT);
+        let background = Rect {
+            x,
+            y: bar_top,
+            width: input_width,
+            height: BAR_HEIGHT,
+        };
+        fill_rect(&mut canvas, background, BAR_BACKGROUND);
+        fill_rect(
+            &mut canvas,
+            Rect {
+                y: bar_top + BAR_HEIGHT - bar,
+                height: bar,
+                ..background
+            },
+            color,
+        );
+        x += input_width + MARGIN;
+    }
+    canvas
+}
+
+/// Encode the debug image as PNG
+pub fn encode_png(image: RgbImage) -> Result<Vec<u8>> {
+    let mut png = Cursor::new(Vec::new());
+    DynamicImage::ImageRgb8(image).write_to(&mut png, ImageOutputFormat::Png)?;
+    Ok(png.into_inner())
+}
+
+/// Draw the crop resized as the model sees it, framed in the given color
+fn draw_input(
+    canvas: &mut RgbImage,
+    source: &RgbImage,
+    crop: Rect,
+    input_shape: (u32, u32),
+    resize: ResizeFilter,
+    (x, y): (u32, u32),
+    color: Rgb<u8>,
+) {
+    let tile = imageops::crop_imm(source, crop.x, crop.y, crop.width, crop.height).to_image();
+    let tile = imageops::resize(&tile, input_shape.0, input_shape.1, resize.filter_type());
+    imageops::replace(canvas, &tile, x as i64, y as i64);
+    let frame = Rect {
+        x: x - 1,
+        y: y - 1,
+        width: input_shape.0 + 2,
+        height: input_shape.1 + 2,
+    };
+    draw_rect(canvas, frame, color, 1);
+}
+
+/// Draw the outline of the rectangle, `thickness` pixels inwards
+fn draw_rect(canvas: &mut RgbImage, rect: Rect, color: Rgb<u8>, thickness: u32) {
+    let thickness = thickness.min(rect.width / 2).min(rect.height / 2).max(1);
+    let edges = [
+        Rect {
+            height: thickness,
+            ..rect
+        },
+        Rect {
+            y: (rect.y + rect.height).saturating_sub(thickness),
+            height: thickness,
+            ..rect
+        },
+        Rect {
+            width: thickness,
+            ..rect
+        },
+        Rect {
+            x: (rect.x + rect.width).saturating_sub(thickness),
+            width: thickness,
+            ..rect
+        },
+    ];
+    for edge in edges {
+        fill_rect(canvas, edge, color);
+    }
+}
+
+/// Fill the rectangle, clipped to the canvas
+fn fill_rect(canvas: &mut RgbImage, rect: Rect, color: Rgb<u8>) {
+    let x_end = (rect.x + rect.width).min(canvas.width());
+    let y_end = (rect.y + rect.height).min(canvas.height());
+    for y in rect.y..y_end {
+        for x in rect.x..x_end {
+            canvas.put_pixel(x, y, color);
+        }
+    }
+}
@@ -0,0 +1,538 @@
+//! Per-model descriptors, the built-in defaults merged with the `--model-config` file.
+//!
+//! The config file is a JSON object keyed by model file name without extension, e.g.
+//! `{"shadows": {"geometry": {"rows": 2, "columns": 3}}}`. Only the given fields are
+//! overridden.
+
+use anyhow::{Context, Result};
+use image::imageops::FilterType;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use super::{inference::Allocator, Layout, Rect};
+
+/// Model descriptor
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Descriptor {
+    pub geometry: Geometry,
+    #[serde(default)]
+    pub preprocess: Preprocess,
+    #[serde(default)]
+    pub tensors: Tensors,
+    #[serde(default)]
+    pub session: SessionOptions,
+    /// sample image of the `--warmup` pass relative to the model directory, a blank image
+    /// of the expected size if not set
+    #[serde(default)]
+    pub warmup: Option<PathBuf>,
+}
+
+/// ONNX Runtime session options, unset fields fall back to the command line
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SessionOptions {
+    /// intra-op threads, `--num-threads` if not set
+    pub intra_threads: Option<u16>,
+    /// inter-op threads, with parallel execution
+    pub inter_threads: Option<u16>,
+    /// run independent branches of the graph in parallel
+    pub parallel_execution: bool,
+    pub optimization: OptimizationLevel,
+    /// plan memory from the shapes of the first run
+    pub memory_pattern: Option<bool>,
+    /// `--allocator` if not set
+    pub allocator: Option<Allocator>,
+    /// save the optimized graph as `<model>.optimized.onnx` and load it on later startups
+    pub save_optimized: bool,
+    /// sessions of the model, `--session-pool-size` if not set
+    pub pool_size: Option<usize>,
+}
+
+/// Graph optimization level
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OptimizationLevel {
+    Disable,
+    /// constant folding and redundant node elimination
+    Basic,
+    /// node fusions
+    Extended,
+    /// layout optimizations, specific to the host
+    #[default]
+    All,
+}
+
+/// Names of the model input and output tensors
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Tensors {
+    /// tile input, classifiers only
+    pub input: String,
+    /// answer image input, pair classifiers only
+    pub input_left: String,
+    /// tile input, pair classifiers only
+    pub input_right: String,
+    /// score output, the first output if not set
+    pub output: Option<String>,
+}
+
+impl Default for Tensors {
+    fn default() -> Self {
+        Self {
+            input: "input".to_owned(),
+            input_left: "input_left".to_owned(),
+            input_right: "input_right".to_owned(),
+            output: None,
+        }
+    }
+}
+
+/// Tile layout of the input image
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Geometry {
+    /// tile grid rows
+    pub rows: u32,
+    /// tile grid columns, as many as fit the image width if not set
+    pub columns: Option<u32>,
+    pub tile_width: u32,
+    pub tile_height: u32,
+    /// answer image compared with every tile, pair classifiers only
+    pub answer: Option<Rect>,
+    /// model input width, every crop is resized to the input size
+    pub input_width: u32,
+    /// model input height
+    pub input_height: u32,
+    /// expected image width, unchecked if not set
+    pub image_width: Option<u32>,
+    /// expected image height, unchecked if not set
+    pub image_height: Option<u32>,
+}
+
+/// Conversion of the crops into model input tensors, every value is
+/// `(pixel * scale - mean) / std` of its channel
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Preprocess {
+    /// filter resizing the crops to the model input size
+    #[serde(default)]
+    pub resize: ResizeFilter,
+    /// factor applied to the 0..255 pixel values
+    #[serde(default = "default_scale")]
+    pub scale: f32,
+    /// per-channel mean, in RGB order
+    #[serde(default)]
+    pub mean: [f32; 3],
+    /// per-channel standard deviation, in RGB order
+    #[serde(default = "default_std")]
+    pub std: [f32; 3],
+    #[serde(default)]
+    pub channels: ChannelOrder,
+    #[serde(default)]
+    pub layout: TensorLayout,
+}
+
+/// Resize filter, from the fastest to the sharpest
+#[derive(clap::ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[value(rename_all = "snake_case")]
+pub enum ResizeFilter {
+    Nearest,
+    Triangle,
+    CatmullRom,
+    Gaussian,
+    #[default]
+    Lanczos3,
+}
+
+/// Channel order of the model input
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChannelOrder {
+    #[default]
+    Rgb,
+    Bgr,
+}
+
+/// Axis order of the model input tensor
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TensorLayout {
+    /// batch, channel, height, width
+    #[default]
+    Nchw,
+    /// batch, height, width, channel
+    Nhwc,
+}
+
+impl Default for Preprocess {
+    fn default() -> Self {
+        Self {
+            resize: ResizeFilter::default(),
+            scale: default_scale(),
+            mean: [0.0; 3],
+            std: default_std(),
+            channels: ChannelOrder::default(),
+            layout: TensorLayout::default(),
+        }
+    }
+}
+
+impl Preprocess {
+    /// Check the normalization, independent of any image
+    pub fn check(&self) -> Result<()> {
+        if !self.scale.is_finite() || self.mean.iter().any(|mean| !mean.is_finite()) {
+            anyhow::bail!("invalid preprocess: scale and mean must be finite");
+        }
+        if self.std.iter().any(|std| !std.is_normal()) {
+            anyhow::bail!("invalid preprocess: std must be finite and non-zero");
+        }
+        Ok(())
+    }
+
+    /// Input tensor shape of `batch` crops of the given size
+    pub fn shape(&self, batch: usize, (width, height): (u32, u32)) -> [usize; 4] {
+        let (width, height) = (width as usize, height as usize);
+        match self.layout {
+            TensorLayout::Nchw => [batch, 3, height, width],
+            TensorLayout::Nhwc => [batch, height, width, 3],
+        }
+    }
+}
+
+fn default_scale() -> f32 {
+    1.0 / 255.0
+}
+
+fn default_std() -> [f32; 3] {
+    [1.0; 3]
+}
+
+impl ResizeFilter {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Nearest => "nearest",
+            Self::Triangle => "triangle",
+            Self::CatmullRom => "catmull_rom",
+            Self::Gaussian => "gaussian",
+            Self::Lanczos3 => "lanczos3",
+        }
+    }
+
+    pub fn filter_type(self) -> FilterType {
+        match self {
+            Self::Nearest => FilterType::Nearest,
+            Self::Triangle => FilterType::Triangle,
+            Self::CatmullRom => FilterType::CatmullRom,
+            Self::Gaussian => FilterType::Gaussian,
+            Self::Lanczos3 => FilterType::Lanczos3,
+        }
+    }
+}
+
+impl Descriptor {
+    /// Pair classifiers: a row of 200px tiles above the 135x200 answer image
+    pub fn pair_classifier() -> Self {
+        Self {
+            geometry: Geometry {
+                rows: 1,
+                columns: None,
+                tile_width: 200,
+                tile_height: 200,
+                answer: Some(Rect {
+                    x: 0,
+                    y: 200,
+                    width: 135,
+                    height: 200,
+                }),
+                input_width: 52,
+                input_height: 52,
+                image_width: None,
+                image_height: Some(400),
+            },
+            preprocess: Preprocess::default(),
+            tensors: Tensors::default(),
+            session: SessionOptions::default(),
+            warmup: None,
+        }
+    }
+
+    /// Classifiers: a 3x2 grid of 100px tiles
+    pub fn classifier() -> Self {
+        Self {
+            geometry: Geometry {
+                rows: 2,
+                columns: Some(3),
+                tile_width: 100,
+                tile_height: 100,
+                answer: None,
+                input_width: 52,
+                input_height: 52,
+                image_width: None,
+                image_height: None,
+            },
+            preprocess: Preprocess::default(),
+            tensors: Tensors::default(),
+            session: SessionOptions::default(),
+            warmup: None,
+        }
+    }
+
+    /// Apply the config file entry of the model, if any
+    pub fn load(self, onnx: &str, config: Option<&Path>) -> Result<Self> {
+        let Some(config) = config else {
+            return Ok(self);
+        };
+        let name = onnx.split('.').next().unwrap_or(onnx);
+        let text = fs::read_to_string(config)
+            .with_context(|| format!("failed to read {}", config.display()))?;
+        let mut entries: Map<String, Value> = serde_json::from_str(&text)
+            .with_context(|| format!("invalid model config {}", config.display()))?;
+        let Some(entry) = entries.remove(name) else {
+            return Ok(self);
+        };
+
+        let mut value = serde_json::to_value(self)?;
+        merge(&mut value, entry);
+        let descriptor: Self = serde_json::from_value(value)
+            .with_context(|| format!("invalid model config of {name}"))?;
+        tracing::debug!(model = name, ?descriptor, "model descriptor");
+        Ok(descriptor)
+    }
+
+    /// Check the descriptor itself, independent of any image
+    pub fn check(&self, pair: bool) -> Result<()> {
+        self.geometry.check(pair)?;
+        self.preprocess.check()
+    }
+}
+
+impl Geometry {
+    /// Check the geometry itself, independent of any image
+    pub fn check(&self, pair: bool) -> Result<()> {
+        if self.rows == 0 || self.columns == Some(0) {
+            anyhow::bail!("invalid geometry: the tile grid is empty");
+        }
+        if self.tile_width == 0 || self.tile_height == 0 {
+            anyhow::bail!("invalid geometry: the tile size is empty");
+        }
+        if self.input_width == 0 || self.input_height == 0 {
+            anyhow::bail!("invalid geometry: the model input size is empty");
+        }
+        match self.answer {
+            Some(answer) if answer.width == 0 || answer.height == 0 => {
+                anyhow::bail!("invalid geometry: the answer region is empty")
+            }
+            None if pair => anyhow::bail!("invalid geometry: pair classifiers need an answer"),
+            _ => Ok(()),
+        }
+    }
+
+    /// Crop rectangles of the image, checking its size against the geometry
+    pub fn layout(&self, width: u32, height: u32) -> Result<Layout> {
+        let columns = self.columns.unwrap_or(width / self.tile_width);
+        let grid = (columns * self.tile_width, self.rows * self.tile_height);
+        let answer = self
+            .answer
+            .map(|answer| (answer.x + answer.width, answer.y + answer.height))
+            .unwrap_or_default();
+
+        let fits = columns > 0
+            && (self.columns.is_some() || width.checked_rem(self.tile_width) == Some(0))
+            && self.image_width.unwrap_or(width) == width
+            && self.image_height.unwrap_or(height) == height
+            && grid.0.max(answer.0) <= width
+            && grid.1.max(answer.1) <= height;
+        if !fits {
+            anyhow::bail!(
+                "Invalid input image size: expected {}, got {width}x{height}",
+                self.expected()
+            );
+        }
+
+        let tiles = (0..self.rows)
+            .flat_map(|row| (0..columns).map(move |column| (row, column)))
+            .map(|(row, column)| Rect {
+                x: column * self.tile_width,
+                y: row * self.tile_height,
+                width: self.tile_width,
+                height: self.tile_height,
+            })
+            .collect();
+        Ok(Layout {
+            answer: self.answer,
+            tiles,
+            input_shape: (self.input_width, self.input_height),
+        })
+    }
+
+    /// Image width the geometry expects with the given number of columns
+    pub fn canonical_width(&self, columns: u32) -> u32 {
+        let answer = self.answer.map_or(0, |answer| answer.x + answer.width);
+        self.image_width
+            .unwrap_or((columns * self.tile_width).max(answer))
+    }
+
+    /// Image size the geometry expects, with a single column if the columns are not set
+    pub fn canonical_size(&self) -> (u32, u32) {
+        (
+            self.canonical_width(self.columns.unwrap_or(1)),
+            self.canonical_height(),
+        )
+    }
+
+    /// Image height the geometry expects
+    pub fn canonical_height(&self) -> u32 {
+        let answer = self.answer.map_or(0, |answer| answer.y + answer.height);
+        self.image_height
+            .unwrap_or((self.rows * self.tile_height).max(answer))
+    }
+
+    /// Human readable expected image size
+    fn expected(&self) -> String {
+        let width = match (self.image_width, self.columns) {
+            (Some(width), _) => width.to_string(),
+            (None, Some(columns)) => format!("at least {}", self.canonical_width(columns)),
+            (None, None) => format!("a multiple of {}", self.tile_width),
+        };
+        let height = match self.image_height {
+            Some(height) => height.to_string(),
+            None => format!("at least {}", self.canonical_height()),
+        };
+        format!("width {width} and height {height}")
+    }
+}
+
+/// Recursively merge the override into the value, objects key by key
+fn merge(value: &mut Value, overrides: Value) {
+    match (value, overrides) {
+        (Value::Object(value), Value::Object(overrides)) => {
+            for (key, item) in overrides {
+                merge(value.entry(key).or_insert(Value::Null), item);
+            }
+        }
+        (value, overrides) => *value = overrides,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pair() -> Geometry {
+        Descriptor::pair_classifier().geometry
+    }
+
+    fn classifier() -> Geometry {
+        Descriptor::classifier().geometry
+    }
+
+    #[test]
+    fn pair_classifier_columns_follow_the_width() {
+        let layout = pair().layout(600, 400).unwrap();
+        assert_eq!(layout.tiles.len(), 3);
+        assert_eq!(
+            layout.tiles[2],
+            Rect {
+                x: 400,
+                y: 0,
+                width: 200,
+                height: 200
+            }
+        );
+        assert_eq!(layout.answer, pair().answer);
+        assert_eq!(layout.input_shape, (52, 52));
+    }
+
+    #[test]
+    fn pair_classifier_sizes() {
+        // a whole number of tiles, at the expected height
+        assert!(pair().layout(200, 400).is_ok());
+        assert!(pair().layout(650, 400).is_err());
+        assert!(pair().layout(600, 401).is_err());
+        assert!(pair().layout(0, 400).is_err());
+        assert!(pair().layout(100, 400).is_err());
+    }
+
+    #[test]
+    fn classifier_grid() {
+        let layout = classifier().layout(300, 200).unwrap();
+        assert_eq!(layout.tiles.len(), 6);
+        assert_eq!(layout.answer, None);
+        // row major
+        assert_eq!((layout.tiles[3].x, layout.tiles[3].y), (0, 100));
+        assert_eq!((layout.tiles[5].x, layout.tiles[5].y), (200, 100));
+    }
+
+    #[test]
+    fn classifier_sizes() {
+        // fixed columns accept larger images, the grid is cropped from the top left
+        assert!(classifier().layout(320, 210).is_ok());
+        assert!(classifier().layout(299, 200).is_err());
+        assert!(classifier().layout(300, 199).is_err());
+    }
+
+    #[test]
+    fn expected_image_size() {
+        let geometry = Geometry {
+            image_width: Some(300),
+            image_height: Some(200),
+            ..classifier()
+        };
+        assert!(geometry.layout(300, 200).is_ok());
+        let err = geometry.layout(320, 200).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid input image size: expected width 300 and height 200, got 320x200"
+        );
+        assert!(classifier()
+            .layout(10, 10)
+            .unwrap_err()
+            .to_string()
+            .contains("width at least 300 and height at least 200"));
+    }
+
+    #[test]
+    fn canonical_sizes() {
+        assert_eq!(pair().canonical_size(), (200, 400));
+        assert_eq!(pair().canonical_width(4), 800);
+        assert_eq!(classifier().canonical_size(), (300, 200));
+    }
+
+    #[test]
+    fn checks_geometry() {
+        assert!(pair().check(true).is_ok());
+        assert!(classifier().check(false).is_ok());
+        assert!(classifier().check(true).is_err());
+        let empty = Geometry {
+            columns: Some(0),
+            ..classifier()
+        };
+        assert!(empty.check(false).is_err());
+    }
+
+    #[test]
+    fn config_overrides_only_the_given_fields() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = dir.path().join("models.json");
+        fs::write(
+            &config,
+            r#"{"shadows": {"geometry": {"rows": 1}, "preprocess": {"resize": "nearest"}}}"#,
+        )
+        .unwrap();
+        let descriptor = Descriptor::classifier()
+            .load("shadows.onnx", Some(&config))
+            .unwrap();
+        assert_eq!(descriptor.geometry.rows, 1);
+        assert_eq!(descriptor.geometry.columns, Some(3));
+        assert_eq!(descriptor.preprocess.resize, ResizeFilter::Nearest);
+        assert_eq!(descriptor.preprocess.scale, default_scale());
+
+        let other = Descriptor::classifier()
+            .load("penguin.onnx", Some(&config))
+            .unwrap();
+        assert_eq!(other.geometry.rows, 2);
+    }
+}
@@ -1,97 +1,85 @@
-use anyhow::Result;
-use image::GenericImageView;
-use ndarray::Array4;
+use image::{imageops, DynamicImage, GenericImageView, RgbImage};
+use ndarray::{s, Array4, ArrayView4, ArrayViewMut3};
+use std::borrow::Cow;
 
-pub fn check_input_image_size(image: &image::DynamicImage) -> Result<()> {
-    let (width, height) = image.dimensions();
-    if height != 400 || width % 200 != 0 {
-        anyhow::bail!("Invalid input image size: {:?}", image.dimensions());
-    }
-    Ok(())
-}
+use super::descriptor::{ChannelOrder, Preprocess, ResizeFilter, TensorLayout};
+use super::Rect;
 
-#[inline]
-pub fn process_pair_classifier_ans_image(
-    image: &mut image::DynamicImage,
-    input_shape: (u32, u32),
-) -> Result<Array4<f32>> {
-    let image = crop_funcaptcha_ans_image(image);
-    let sub_image = image.resize_exact(
-        input_shape.0,
-        input_shape.1,
-        image::imageops::FilterType::Lanczos3,
-    );
-    let normalized_vec: Vec<f32> = sub_image
-        .into_rgb8()
-        .into_raw()
-        .into_iter()
-        .map(|v| v as f32 / 255.0)
-        .collect();
-    let normalized_image = Array4::from_shape_vec(
-        (1, input_shape.0 as usize, input_shape.1 as usize, 3),
-        normalized_vec,
-    )?;
-    Ok(normalized_image.permuted_axes([0, 3, 1, 2]))
+/// Pixels of the image as RGB8, borrowed when it already is
+pub fn rgb8(image: &DynamicImage) -> Cow<'_, RgbImage> {
+    match image.as_rgb8() {
+        Some(rgb) => Cow::Borrowed(rgb),
+        None => Cow::Owned(image.to_rgb8()),
+    }
 }
 
+/// Crop the regions and resize them to the model input, as a batch tensor in the
+/// layout of the preprocess
 #[inline]
-pub fn process_pair_classifier_image(
-    image: &image::DynamicImage,
-    index: (u32, u32),
+#[tracing::instrument(level = "debug", skip(image, crops, preprocess))]
+pub fn process_image(
+    image: &RgbImage,
+    crops: &[Rect],
     input_shape: (u32, u32),
-) -> Result<Array4<f32>> {
-    let (x, y) = (index.1 * 200, index.0 * 200);
-    let sub_image = image.crop_imm(x, y, 200, 200).resize_exact(
-        input_shape.0,
-        input_shape.1,
-        image::imageops::FilterType::Lanczos3,
-    );
-    let normalized_vec: Vec<f32> = sub_image
-        .into_rgb8()
-        .into_raw()
-        .into_iter()
-        .map(|v| v as f32 / 255.0)
-        .collect();
-    let normalized_image = Array4::from_shape_vec(
-        (1, input_shape.0 as usize, input_shape.1 as usize, 3),
-        normalized_vec,
-    )?;
-    Ok(normalized_image.permuted_axes([0, 3, 1, 2]))
+    preprocess: &Preprocess,
+) -> Array4<f32> {
+    let mut batch = Array4::zeros(preprocess.shape(crops.len(), input_shape));
+    // Write through a channels-first view, whatever the memory layout
+    let mut view = batch.view_mut();
+    if preprocess.layout == TensorLayout::Nhwc {
+        view = view.permuted_axes([0, 3, 1, 2]);
+    }
+    for (i, &crop) in crops.iter().enumerate() {
+        write_input(image, crop, preprocess, view.slice_mut(s![i, .., .., ..]));
+    }
+    batch
 }
 
-#[inline]
-pub fn process_classifier_image(
-    image: &mut image::DynamicImage,
-    index: u32,
-    input_shape: (u32, u32),
-) -> Result<Array4<f32>> {
-    let sub_image = crop_funcaptcha_image(image, (index / 3, index % 3), 100).resize_exact(
-        input_shape.0,
-        input_shape.1,
-        image::imageops::FilterType::Lanczos3,
-    );
-    let normalized_vec: Vec<f32> = sub_image
-        .into_rgb8()
-        .into_raw()
-        .into_iter()
-        .map(|v| v as f32 / 255.0)
-        .collect();
-    let normalized_image = Array4::from_shape_vec(
-        (1, input_shape.0 as usize, input_shape.1 as usize, 3),
-        normalized_vec,
-    )?;
-    Ok(normalized_image.permuted_axes([0, 3, 1, 2]))
+/// Channels-first view of a tensor in the given layout
+pub fn nchw(tensor: ArrayView4<'_, f32>, layout: TensorLayout) -> ArrayView4<'_, f32> {
+    match layout {
+        TensorLayout::Nchw => tensor,
+        TensorLayout::Nhwc => tensor.permuted_axes([0, 3, 1, 2]),
+    }
 }
 
-pub fn crop_funcaptcha_image(
-    image: &mut image::DynamicImage,
-    index: (u32, u32),
-    width: u32,
-) -> image::DynamicImage {
-    let (x, y) = (index.1 * width, index.0 * width);
-    image.crop_imm(x, y, width, width)
-}
+/// Crop the region and resize it into the `(3, height, width)` tensor, normalized
+fn write_input(image: &RgbImage, crop: Rect, preprocess: &Preprocess, mut out: ArrayViewMut3<f32>) {
+    let (height, width) = (out.shape()[1] as u32, out.shape()[2] as u32);
+    let view = imageops::crop_imm(image, crop.x, crop.y, crop.width, crop.height);
+    let resize = preprocess.resize;
 
-pub fn crop_funcaptcha_ans_image(image: &mut image::DynamicImage) -> image::DynamicImage {
-    image.crop(0, 200, 135, 400)
+    // `(value * scale - mean) / std` as `value * factor + offset`, in RGB order
+    let factor: [f32; 3] = std::array::from_fn(|c| preprocess.scale / preprocess.std[c]);
+    let offset: [f32; 3] = std::array::from_fn(|c| -preprocess.mean[c] / preprocess.std[c]);
+    // source RGB channel of every input channel
+    let channels = match preprocess.channels {
+        ChannelOrder::Rgb => [0, 1, 2],
+        ChannelOrder::Bgr => [2, 1, 0],
+    };
+    let mut write = |x: u32, y: u32, pixel: [u8; 3]| {
+        for (c, from) in channels.into_iter().enumerate() {
+            out[[c, y as usize, x as usize]] = pixel[from] as f32 * factor[from] + offset[from];
+        }
+    };
+    if (crop.width, crop.height) == (width, height) {
+        for (x, y, pixel) in view.pixels() {
+            write(x, y, pixel.0);
+        }
+    } else if resize == ResizeFilter::Nearest {
+        // Sample the pixel centers directly, without an intermediate image
+        for y in 0..height {
+            let sy = ((y as f32 + 0.5) * crop.height as f32 / height as f32) as u32;
+            for x in 0..width {
+                let sx = ((x as f32 + 0.5) * crop.width as f32 / width as f32) as u32;
+                let pixel = view.get_pixel(sx.min(crop.width - 1), sy.min(crop.height - 1));
+                write(x, y, pixel.0);
+            }
+        }
+    } else {
+        let resized = imageops::resize(&*view, width, height, resize.filter_type());
+        for (x, y, pixel) in resized.enumerate_pixels() {
+            write(x, y, pixel.0);
+        }
+    }
 }
@@ -0,0 +1,81 @@
+//! Inference backends: ONNX Runtime (`ort` feature, default) or tract (`tract` feature).
+//!
+//! tract takes precedence when both are enabled, build with
+//! `--no-default-features --features tract` to drop the native ONNX Runtime library.
+
+use anyhow::Result;
+use ndarray::{Array4, ArrayD};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+use super::descriptor::SessionOptions;
+use crate::ModelArgs;
+
+#[cfg(all(feature = "ort", not(feature = "tract")))]
+mod onnxruntime;
+mod pool;
+#[cfg(feature = "tract")]
+mod tract;
+
+pub use pool::{PooledSession, SessionPool};
+
+#[cfg(not(any(feature = "ort", feature = "tract")))]
+compile_error!("enable an inference backend: the `ort` or `tract` feature");
+
+/// Name of the compiled-in backend
+#[cfg(feature = "tract")]
+pub const BACKEND: &str = "tract";
+#[cfg(all(feature = "ort", not(feature = "tract")))]
+pub const BACKEND: &str = "onnxruntime";
+
+/// Execution provider allocator, ONNX Runtime only
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Allocator {
+    #[default]
+    Device,
+    Arena,
+}
+
+impl Allocator {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Device => "device",
+            Self::Arena => "arena",
+        }
+    }
+}
+
+/// Input or output of the model signature
+#[derive(Debug, Clone)]
+pub struct TensorInfo {
+    pub name: String,
+    /// dimensions of a `f32` tensor, negative if dynamic, `None` for other types
+    pub dimensions: Option<Vec<i64>>,
+}
+
+/// Loaded model, shared by every request
+pub trait Inference: Send + Sync {
+    fn inputs(&self) -> &[TensorInfo];
+
+    fn outputs(&self) -> &[TensorInfo];
+
+    /// Run the model on the named inputs, returns the named output
+    fn run(&self, inputs: Vec<(&str, Array4<f32>)>, output: &str) -> Result<ArrayD<f32>>;
+}
+
+/// Load the model file with the compiled-in backend
+pub fn load(
+    model_file: &Path,
+    args: &ModelArgs,
+    options: &SessionOptions,
+) -> Result<Box<dyn Inference>> {
+    #[cfg(feature = "tract")]
+    return Ok(Box::new(tract::TractSession::load(
+        model_file, args, options,
+    )?));
+    #[cfg(all(feature = "ort", not(feature = "tract")))]
+    return Ok(Box::new(onnxruntime::OrtSession::load(
+        model_file, args, options,
+    )?));
+}
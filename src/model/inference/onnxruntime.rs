@@ -0,0 +1,128 @@
+//! ONNX Runtime backend
+
+use anyhow::Result;
+use ndarray::{Array4, ArrayD};
+use ort::{AllocatorType, GraphOptimizationLevel, Session, TensorElementType, Value, ValueType};
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use super::{Allocator, Inference, TensorInfo};
+use crate::model::descriptor::{OptimizationLevel, SessionOptions};
+use crate::ModelArgs;
+
+pub struct OrtSession {
+    session: Session,
+    inputs: Vec<TensorInfo>,
+    outputs: Vec<TensorInfo>,
+}
+
+impl OrtSession {
+    pub fn load(model_file: &Path, args: &ModelArgs, options: &SessionOptions) -> Result<Self> {
+        let allocator = match options.allocator.unwrap_or(args.allocator) {
+            Allocator::Device => AllocatorType::Device,
+            Allocator::Arena => AllocatorType::Arena,
+        };
+        let optimization = match options.optimization {
+            OptimizationLevel::Disable => GraphOptimizationLevel::Disable,
+            OptimizationLevel::Basic => GraphOptimizationLevel::Level1,
+            OptimizationLevel::Extended => GraphOptimizationLevel::Level2,
+            OptimizationLevel::All => GraphOptimizationLevel::Level3,
+        };
+
+        // A saved optimized graph is loaded as is, until the model is updated
+        let optimized = optimized_path(model_file);
+        let reuse = options.save_optimized && newer(&optimized, model_file);
+        let mut builder = Session::builder()?
+            .with_optimization_level(if reuse {
+                GraphOptimizationLevel::Disable
+            } else {
+                optimization
+            })?
+            .with_parallel_execution(options.parallel_execution)?
+            .with_intra_threads(options.intra_threads.unwrap_or(args.num_threads) as i16)?
+            .with_allocator(allocator)?;
+        if let Some(threads) = options.inter_threads {
+            builder = builder.with_inter_threads(threads as i16)?;
+        }
+        if let Some(memory_pattern) = options.memory_pattern {
+            builder = builder.with_memory_pattern(memory_pattern)?;
+        }
+        let session = if reuse {
+            tracing::info!("loading optimized model {}", optimized.display());
+            builder.with_model_from_file(&optimized)?
+        } else {
+            if options.save_optimized {
+                builder = builder.with_optimized_model_path(optimized.to_string_lossy())?;
+            }
+            builder.with_model_from_file(model_file)?
+        };
+
+        let inputs = session
+            .inputs
+            .iter()
+            .map(|input| tensor_info(&input.name, &input.input_type))
+            .collect();
+        let outputs = session
+            .outputs
+            .iter()
+            .map(|output| tensor_info(&output.name, &output.output_type))
+            .collect();
+        Ok(Self {
+            session,
+            inputs,
+            outputs,
+        })
+    }
+}
+
+impl Inference for OrtSession {
+    fn inputs(&self) -> &[TensorInfo] {
+        &self.inputs
+    }
+
+    fn outputs(&self) -> &[TensorInfo] {
+        &self.outputs
+    }
+
+    fn run(&self, inputs: Vec<(&str, Array4<f32>)>, output: &str) -> Result<ArrayD<f32>> {
+        let inputs = inputs
+            .into_iter()
+            .map(|(name, array)| Ok((name.to_owned(), Value::try_from(array)?)))
+            .collect::<Result<HashMap<String, Value>>>()?;
+        let outputs = self.session.run(inputs)?;
+        let tensor = outputs[output].extract_tensor::<f32>()?;
+        let array = tensor.view().into_owned();
+        Ok(array)
+    }
+}
+
+/// `<model>.optimized.onnx` next to the model
+fn optimized_path(model_file: &Path) -> PathBuf {
+    model_file.with_extension("optimized.onnx")
+}
+
+/// Whether the file exists and was modified after the other one
+fn newer(path: &Path, than: &Path) -> bool {
+    let modified = |path: &Path| fs::metadata(path).and_then(|metadata| metadata.modified());
+    match (modified(path), modified(than)) {
+        (Ok(modified), Ok(than)) => modified >= than,
+        _ => false,
+    }
+}
+
+fn tensor_info(name: &str, value_type: &ValueType) -> TensorInfo {
+    let dimensions = match value_type {
+        ValueType::Tensor {
+            ty: TensorElementType::Float32,
+            dimensions,
+        } => Some(dimensions.clone()),
+        _ => None,
+    };
+    TensorInfo {
+        name: name.to_owned(),
+        dimensions,
+    }
+}
@@ -0,0 +1,83 @@
+//! Pool of sessions of one model, so parallel requests do not share one session
+
+use std::{
+    ops::Deref,
+    sync::{Condvar, Mutex},
+};
+
+use super::Inference;
+
+pub struct SessionPool {
+    sessions: Vec<Box<dyn Inference>>,
+    /// indexes of the sessions not checked out
+    free: Mutex<Vec<usize>>,
+    available: Condvar,
+}
+
+/// Session checked out of the pool, returned when dropped
+pub struct PooledSession<'a> {
+    pool: &'a SessionPool,
+    index: usize,
+}
+
+impl SessionPool {
+    pub fn new(sessions: Vec<Box<dyn Inference>>) -> Self {
+        assert!(!sessions.is_empty(), "empty session pool");
+        Self {
+            free: Mutex::new((0..sessions.len()).rev().collect()),
+            sessions,
+            available: Condvar::new(),
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.sessions.len()
+    }
+
+    /// Any session, for its signature
+    pub fn first(&self) -> &dyn Inference {
+        self.sessions[0].as_ref()
+    }
+
+    /// Every session of the pool, without checkout
+    pub fn sessions(&self) -> impl Iterator<Item = &dyn Inference> {
+        self.sessions.iter().map(|session| session.as_ref())
+    }
+
+    /// Check out a free session, waiting for one if all are busy. A single session is
+    /// shared without checkout, sessions are safe to run concurrently.
+    pub fn checkout(&self) -> PooledSession<'_> {
+        if self.sessions.len() == 1 {
+            return PooledSession {
+                pool: self,
+                index: 0,
+            };
+        }
+        let mut free = self.free.lock().unwrap_or_else(|e| e.into_inner());
+        loop {
+            if let Some(index) = free.pop() {
+                return PooledSession { pool: self, index };
+            }
+            free = self.available.wait(free).unwrap_or_else(|e| e.into_inner());
+        }
+    }
+}
+
+impl Deref for PooledSession<'_> {
+    type Target = dyn Inference;
+
+    fn deref(&self) -> &Self::Target {
+        self.pool.sessions[self.index].as_ref()
+    }
+}
+
+impl Drop for PooledSession<'_> {
+    fn drop(&mut self) {
+        if self.pool.sessions.len() == 1 {
+            return;
+        }
+        let mut free = self.pool.free.lock().unwrap_or_else(|e| e.into_inner());
+        free.push(self.index);
+        self.pool.available.notify_one();
+    }
+}
@@ -0,0 +1,92 @@
+//! Pure-Rust tract backend, single-threaded per run
+
+use anyhow::Result;
+use ndarray::{Array4, ArrayD};
+use std::path::Path;
+use tract_onnx::prelude::*;
+
+use super::{Inference, TensorInfo};
+use crate::model::descriptor::SessionOptions;
+use crate::ModelArgs;
+
+pub struct TractSession {
+    plan: TypedRunnableModel<TypedModel>,
+    inputs: Vec<TensorInfo>,
+    outputs: Vec<TensorInfo>,
+}
+
+impl TractSession {
+    pub fn load(model_file: &Path, args: &ModelArgs, options: &SessionOptions) -> Result<Self> {
+        if args.num_threads > 1 || options.intra_threads.is_some_and(|threads| threads > 1) {
+            tracing::debug!("tract runs single-threaded, ignoring the thread counts");
+        }
+        let model = tract_onnx::onnx()
+            .model_for_path(model_file)?
+            .into_optimized()?;
+
+        let inputs = model
+            .input_outlets()?
+            .iter()
+            .map(|&outlet| tensor_info(&model, outlet))
+            .collect::<Result<Vec<TensorInfo>>>()?;
+        let outputs = model
+            .output_outlets()?
+            .iter()
+            .map(|&outlet| tensor_info(&model, outlet))
+            .collect::<Result<Vec<TensorInfo>>>()?;
+        Ok(Self {
+            plan: model.into_runnable()?,
+            inputs,
+            outputs,
+        })
+    }
+}
+
+impl Inference for TractSession {
+    fn inputs(&self) -> &[TensorInfo] {
+        &self.inputs
+    }
+
+    fn outputs(&self) -> &[TensorInfo] {
+        &self.outputs
+    }
+
+    fn run(&self, inputs: Vec<(&str, Array4<f32>)>, output: &str) -> Result<ArrayD<f32>> {
+        // tract takes the inputs in model order
+        let mut inputs = inputs;
+        let mut values = TVec::new();
+        for info in &self.inputs {
+            let index = inputs
+                .iter()
+                .position(|(name, _)| *name == info.name)
+                .ok_or_else(|| anyhow::anyhow!("missing input {:?}", info.name))?;
+            let (_, array) = inputs.swap_remove(index);
+            values.push(Tensor::from(array).into_tvalue());
+        }
+
+        let index = self
+            .outputs
+            .iter()
+            .position(|info| info.name == output)
+            .ok_or_else(|| anyhow::anyhow!("unknown output {output:?}"))?;
+        let outputs = self.plan.run(values)?;
+        let array = outputs[index].to_array_view::<f32>()?.to_owned();
+        Ok(array)
+    }
+}
+
+/// Signature of an input or output, named after its ONNX tensor
+fn tensor_info(model: &TypedModel, outlet: OutletId) -> Result<TensorInfo> {
+    let fact = model.outlet_fact(outlet)?;
+    let name = model
+        .outlet_label(outlet)
+        .unwrap_or(model.node(outlet.node).name.as_str())
+        .to_owned();
+    let dimensions = (fact.datum_type == f32::datum_type()).then(|| {
+        fact.shape
+            .iter()
+            .map(|dimension| dimension.to_i64().unwrap_or(-1))
+            .collect()
+    });
+    Ok(TensorInfo { name, dimensions })
+}
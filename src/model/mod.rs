@@ -1,21 +1,32 @@
 mod base;
 mod coordinatesmatch;
+pub mod debug;
+pub mod descriptor;
 mod hopscotch_highsec;
 mod image_processing;
+pub mod inference;
 mod m3d_rollball_objects;
+pub mod normalize;
 mod penguin;
+pub mod quantized;
 mod shadows;
 mod train_coordinates;
 
+use self::descriptor::Descriptor;
 use self::{
     coordinatesmatch::CoordinatesMatchPredictor, hopscotch_highsec::HopscotchHighsecPredictor,
     m3d_rollball_objects::M3DRotationPredictor, penguin::PenguinPredictor,
     shadows::ShadowsPredictor, train_coordinates::TrainCoordinatesPredictor,
 };
-use crate::BootArgs;
-use anyhow::Result;
+use crate::{homedir, ModelArgs};
+use anyhow::{Context, Result};
 use image::DynamicImage;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use std::{
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Instant,
+};
 use tokio::sync::OnceCell;
 
 static M3D_ROLLBALL_PREDICTOR: OnceCell<M3DRotationPredictor> = OnceCell::const_new();
@@ -27,26 +38,177 @@ static SHADOWS_PREDICTOR: OnceCell<ShadowsPredictor> = OnceCell::const_new();
 
 /// Predictor trait
 pub trait Predictor: Send + Sync {
-    fn predict(&self, image: DynamicImage) -> Result<i32>;
-}
-
-/// Load the models predictor
-pub fn init_predictor(args: &BootArgs) -> Result<()> {
-    set_predictor(&M3D_ROLLBALL_PREDICTOR, || M3DRotationPredictor::new(args))?;
-    set_predictor(&COORDINATES_MATCH_PREDICTOR, || {
-        CoordinatesMatchPredictor::new(args)
-    })?;
-    set_predictor(&HOPSCOTCH_HIGHSEC_PREDICTOR, || {
-        HopscotchHighsecPredictor::new(args)
-    })?;
-    set_predictor(&TRAIN_COORDINATES_PREDICTOR, || {
-        TrainCoordinatesPredictor::new(args)
-    })?;
-    set_predictor(&PENGUIN_PREDICTOR, || PenguinPredictor::new(args))?;
-    set_predictor(&SHADOWS_PREDICTOR, || ShadowsPredictor::new(args))?;
+    fn predict(&self, image: DynamicImage) -> Result<Prediction>;
+
+    /// Regions of the image the preprocessor crops, in tile order
+    fn layout(&self, image: &DynamicImage) -> Result<Layout>;
+
+    /// Descriptor of the model
+    fn descriptor(&self) -> &Descriptor;
+
+    /// Run the image through every session of the model, so the first request does not
+    /// pay for the lazy allocations
+    fn warm_up(&self, image: &DynamicImage) -> Result<()>;
+}
+
+/// Pixel rectangle of an image
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Crop rectangles of an image, as extracted by the preprocessor
+#[derive(Debug, Clone, Serialize)]
+pub struct Layout {
+    /// answer image compared with every tile, pair classifiers only
+    pub answer: Option<Rect>,
+    /// tiles scored by the model, in answer index order
+    pub tiles: Vec<Rect>,
+    /// size every crop is resized to before inference
+    pub input_shape: (u32, u32),
+}
+
+/// Prediction of a single image
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Prediction {
+    /// index of the highest scoring tile
+    pub answer: i32,
+    /// raw model score of every tile
+    pub scores: Vec<f32>,
+}
+
+impl Prediction {
+    /// Create a prediction choosing the highest scoring tile
+    pub fn from_scores(scores: Vec<f32>) -> Self {
+        let mut max_prediction = f32::NEG_INFINITY;
+        let mut max_index = 0;
+        for (i, &score) in scores.iter().enumerate() {
+            if score > max_prediction {
+                max_prediction = score;
+                max_index = i;
+            }
+        }
+        Self {
+            answer: max_index as i32,
+            scores,
+        }
+    }
+
+    /// Softmax probability of every tile
+    pub fn probabilities(&self) -> Vec<f32> {
+        let max = self
+            .scores
+            .iter()
+            .copied()
+            .fold(f32::NEG_INFINITY, f32::max);
+        let exp = self
+            .scores
+            .iter()
+            .map(|score| (score - max).exp())
+            .collect::<Vec<f32>>();
+        let sum: f32 = exp.iter().sum();
+        exp.into_iter().map(|e| e / sum).collect()
+    }
+
+    /// Softmax probability of the chosen tile over all tiles
+    pub fn confidence(&self) -> f32 {
+        let Some(&max) = self.scores.get(self.answer as usize) else {
+            return 0.0;
+        };
+        let sum: f32 = self.scores.iter().map(|score| (score - max).exp()).sum();
+        1.0 / sum
+    }
+}
+
+/// Load the models predictor, and warm them up with `--warmup`
+pub fn init_predictor(args: &ModelArgs) -> Result<()> {
+    let mut warmed = Vec::new();
+    for model_type in ModelType::ALL {
+        init_model(model_type, args)?;
+        // model types sharing a model are warmed up once
+        if args.warmup && !warmed.contains(&model_type.onnx()) {
+            warm_up(model_type, args)?;
+            warmed.push(model_type.onnx());
+        }
+    }
+    Ok(())
+}
+
+/// Run the warm-up pass of a loaded model, on its sample image or a blank image
+fn warm_up(model_type: ModelType, args: &ModelArgs) -> Result<()> {
+    let predictor = get_predictor(model_type)?;
+    let descriptor = predictor.descriptor();
+    let image = match descriptor.warmup.as_deref() {
+        Some(path) => {
+            // relative to the model directory, the working directory changes when daemonized
+            let path = model_dir(args.model_dir.as_deref()).join(path);
+            image::open(&path)
+                .with_context(|| format!("failed to read warm-up image {}", path.display()))?
+        }
+        None => {
+            let (width, height) = descriptor.geometry.canonical_size();
+            DynamicImage::new_rgb8(width, height)
+        }
+    };
+    let start = Instant::now();
+    predictor
+        .warm_up(&image)
+        .with_context(|| format!("warm-up of {} failed", model_type.as_str()))?;
+    tracing::info!(
+        model = model_type.as_str(),
+        elapsed_ms = start.elapsed().as_millis() as u64,
+        "warmed up"
+    );
     Ok(())
 }
 
+/// Load the predictor of a single model type, unless it is already loaded
+pub fn init_model(model_type: ModelType, args: &ModelArgs) -> Result<()> {
+    match model_type {
+        ModelType::M3dRollballAnimals | ModelType::M3dRollballObjects => {
+            set_predictor(&M3D_ROLLBALL_PREDICTOR, || M3DRotationPredictor::new(args))
+        }
+        ModelType::Coordinatesmatch => set_predictor(&COORDINATES_MATCH_PREDICTOR, || {
+            CoordinatesMatchPredictor::new(args)
+        }),
+        ModelType::HopscotchHighsec => set_predictor(&HOPSCOTCH_HIGHSEC_PREDICTOR, || {
+            HopscotchHighsecPredictor::new(args)
+        }),
+        ModelType::TrainCoordinates => set_predictor(&TRAIN_COORDINATES_PREDICTOR, || {
+            TrainCoordinatesPredictor::new(args)
+        }),
+        ModelType::Penguin => set_predictor(&PENGUIN_PREDICTOR, || PenguinPredictor::new(args)),
+        ModelType::Shadows => set_predictor(&SHADOWS_PREDICTOR, || ShadowsPredictor::new(args)),
+    }
+}
+
+/// Create a standalone predictor, not shared with `get_predictor`
+pub fn new_predictor(model_type: ModelType, args: &ModelArgs) -> Result<Box<dyn Predictor>> {
+    let predictor: Box<dyn Predictor> = match model_type {
+        ModelType::M3dRollballAnimals | ModelType::M3dRollballObjects => {
+            Box::new(M3DRotationPredictor::new(args)?)
+        }
+        ModelType::Coordinatesmatch => Box::new(CoordinatesMatchPredictor::new(args)?),
+        ModelType::HopscotchHighsec => Box::new(HopscotchHighsecPredictor::new(args)?),
+        ModelType::TrainCoordinates => Box::new(TrainCoordinatesPredictor::new(args)?),
+        ModelType::Penguin => Box::new(PenguinPredictor::new(args)?),
+        ModelType::Shadows => Box::new(ShadowsPredictor::new(args)?),
+    };
+    Ok(predictor)
+}
+
+/// Model directory, `~/.funcaptcha_models` if not set
+pub fn model_dir(model_dir: Option<&Path>) -> PathBuf {
+    model_dir.map(|x| x.to_owned()).unwrap_or_else(|| {
+        homedir::home_dir()
+            .unwrap_or(PathBuf::new())
+            .join(".funcaptcha_models")
+    })
+}
+
 /// Get the model predictor for the given model type
 pub fn get_predictor(model_type: ModelType) -> Result<&'static dyn Predictor> {
     let predictor = match model_type {
@@ -67,6 +229,9 @@ where
     P: Predictor,
     F: FnOnce() -> Result<P>,
 {
+    if cell.initialized() {
+        return Ok(());
+    }
     cell.set(creator()?)
         .map_err(|_| anyhow::anyhow!("failed to load models"))
 }
@@ -81,7 +246,7 @@ where
     Ok(predictor as &'static dyn Predictor)
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum ModelType {
     M3dRollballAnimals,
     M3dRollballObjects,
@@ -92,33 +257,109 @@ pub enum ModelType {
     Shadows,
 }
 
+impl ModelType {
+    /// All the model type names
+    pub const NAMES: [&'static str; 7] = [
+        "3d_rollball_animals",
+        "3d_rollball_objects",
+        "coordinatesmatch",
+        "hopscotch_highsec",
+        "train_coordinates",
+        "penguin",
+        "shadows",
+    ];
+
+    /// All the model types, in the same order as the names
+    pub const ALL: [ModelType; 7] = [
+        ModelType::M3dRollballAnimals,
+        ModelType::M3dRollballObjects,
+        ModelType::Coordinatesmatch,
+        ModelType::HopscotchHighsec,
+        ModelType::TrainCoordinates,
+        ModelType::Penguin,
+        ModelType::Shadows,
+    ];
+
+    /// Model type name, e.g. 3d_rollball_animals
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ModelType::M3dRollballAnimals => "3d_rollball_animals",
+            ModelType::M3dRollballObjects => "3d_rollball_objects",
+            ModelType::Coordinatesmatch => "coordinatesmatch",
+            ModelType::HopscotchHighsec => "hopscotch_highsec",
+            ModelType::TrainCoordinates => "train_coordinates",
+            ModelType::Penguin => "penguin",
+            ModelType::Shadows => "shadows",
+        }
+    }
+
+    /// Model file name, both 3d_rollball types share one model
+    pub fn onnx(&self) -> &'static str {
+        match self {
+            ModelType::M3dRollballAnimals | ModelType::M3dRollballObjects => {
+                "3d_rollball_objects_v2.onnx"
+            }
+            ModelType::Coordinatesmatch => "coordinatesmatch.onnx",
+            ModelType::HopscotchHighsec => "hopscotch_highsec.onnx",
+            ModelType::TrainCoordinates => "train_coordinates.onnx",
+            ModelType::Penguin => "penguin.onnx",
+            ModelType::Shadows => "shadows.onnx",
+        }
+    }
+}
+
+impl FromStr for ModelType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        ModelType::ALL
+            .into_iter()
+            .find(|model| model.as_str() == s)
+            .ok_or_else(|| anyhow::anyhow!("unknown model type: {s}"))
+    }
+}
+
 impl<'de> Deserialize<'de> for ModelType {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
         D: serde::Deserializer<'de>,
     {
         let s = String::deserialize(deserializer)?;
+        s.parse()
+            .map_err(|_| serde::de::Error::unknown_variant(&s, &ModelType::NAMES))
+    }
+}
 
-        match s.as_str() {
-            "3d_rollball_animals" => Ok(ModelType::M3dRollballAnimals),
-            "3d_rollball_objects" => Ok(ModelType::M3dRollballObjects),
-            "coordinatesmatch" => Ok(ModelType::Coordinatesmatch),
-            "hopscotch_highsec" => Ok(ModelType::HopscotchHighsec),
-            "train_coordinates" => Ok(ModelType::TrainCoordinates),
-            "penguin" => Ok(ModelType::Penguin),
-            "shadows" => Ok(ModelType::Shadows),
-            _ => Err(serde::de::Error::unknown_variant(
-                &s,
-                &[
-                    "3d_rollball_animals",
-                    "3d_rollball_objects",
-                    "coordinatesmatch",
-                    "hopscotch_highsec",
-                    "train_coordinates",
-                    "penguin",
-                    "shadows",
-                ],
-            )),
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_scores_picks_the_highest_score() {
+        assert_eq!(Prediction::from_scores(vec![0.1, 2.5, -1.0, 2.4]).answer, 1);
+        assert_eq!(Prediction::from_scores(vec![-3.0, -2.0, -5.0]).answer, 1);
+        // ties keep the first tile
+        assert_eq!(Prediction::from_scores(vec![1.0, 1.0]).answer, 0);
+        assert_eq!(Prediction::from_scores(Vec::new()).answer, 0);
+    }
+
+    #[test]
+    fn confidence_is_the_softmax_of_the_answer() {
+        let prediction = Prediction::from_scores(vec![0.0, 0.0, 0.0, 0.0]);
+        assert!((prediction.confidence() - 0.25).abs() < 1e-6);
+
+        let prediction = Prediction::from_scores(vec![1.0, 3.0, 0.5]);
+        let probabilities = prediction.probabilities();
+        assert!((prediction.confidence() - probabilities[1]).abs() < 1e-6);
+        assert!((probabilities.iter().sum::<f32>() - 1.0).abs() < 1e-6);
+
+        // large scores do not overflow
+        let prediction = Prediction::from_scores(vec![1000.0, 0.0]);
+        assert!((prediction.confidence() - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn confidence_of_an_empty_prediction() {
+        assert_eq!(Prediction::from_scores(Vec::new()).confidence(), 0.0);
     }
 }
@@ -0,0 +1,326 @@
+//! Input image normalization: rescaled screenshots, padding and answer strips
+
+use anyhow::Result;
+use image::{imageops::FilterType, DynamicImage, Rgb};
+use serde::Serialize;
+
+use super::descriptor::Geometry;
+
+/// Relative size tolerance when matching the image against the geometry
+const TOLERANCE: f32 = 0.02;
+/// Largest channel difference of a border pixel from the border color
+const BORDER_TOLERANCE: u8 = 16;
+
+/// Change made to an input image before prediction
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Adjustment {
+    /// uniform borders removed, in pixels
+    Trimmed {
+        left: u32,
+        top: u32,
+        right: u32,
+        bottom: u32,
+    },
+    /// extra strip below the layout removed, in pixels
+    Cropped { bottom: u32 },
+    /// resized to the size the geometry expects
+    Scaled {
+        from: (u32, u32),
+        to: (u32, u32),
+        factor: f32,
+    },
+}
+
+/// Fit the image to the geometry, returns the adjustments made.
+///
+/// Images that already fit are returned as is. Otherwise the image is rescaled, then
+/// trimmed of its uniform borders and rescaled, and as a last resort an extra strip below
+/// the layout is cropped.
+pub fn normalize(
+    image: DynamicImage,
+    geometry: &Geometry,
+) -> Result<(DynamicImage, Vec<Adjustment>)> {
+    if canonical(geometry, image.width(), image.height()) {
+        return Ok((image, Vec::new()));
+    }
+
+    let err = match rescale(&image, geometry, false) {
+        Ok(rescaled) => return Ok(logged(rescaled)),
+        Err(err) => err,
+    };
+    let trimmed = trim_borders(&image);
+    if let Some((trimmed, trim)) = trimmed.as_ref() {
+        if let Ok((image, mut adjustments)) = rescale(trimmed, geometry, false) {
+            adjustments.insert(0, trim.clone());
+            return Ok(logged((image, adjustments)));
+        }
+    }
+    if geometry.columns.is_some() {
+        let (image, trim) = match trimmed {
+            Some((trimmed, trim)) => (trimmed, Some(trim)),
+            None => (image, None),
+        };
+        if let Ok((image, mut adjustments)) = rescale(&image, geometry, true) {
+            adjustments.splice(0..0, trim);
+            return Ok(logged((image, adjustments)));
+        }
+    }
+    Err(err)
+}
+
+fn logged(normalized: (DynamicImage, Vec<Adjustment>)) -> (DynamicImage, Vec<Adjustment>) {
+    tracing::debug!(adjustments = ?normalized.1, "normalized image");
+    normalized
+}
+
+/// Rescale the image to the geometry, optionally cropping an extra strip below the layout
+fn rescale(
+    image: &DynamicImage,
+    geometry: &Geometry,
+    crop_strip: bool,
+) -> Result<(DynamicImage, Vec<Adjustment>)> {
+    let (width, height) = (image.width(), image.height());
+    if canonical(geometry, width, height) {
+        return Ok((image.clone(), Vec::new()));
+    }
+
+    let (w, h) = (width as f32, height as f32);
+    let canonical_height = geometry.canonical_height() as f32;
+    let grid_height = (geometry.rows * geometry.tile_height) as f32;
+    let mut adjustments = Vec::new();
+
+    let (image, target) = match geometry.columns {
+        // The width gives the scale, anything below the layout is an extra strip
+        Some(columns) => {
+            let canonical_width = geometry.canonical_width(columns);
+            let scale = w / canonical_width as f32;
+            let expected = canonical_height * scale;
+            if close(h, expected) {
+                (
+                    image.clone(),
+                    (canonical_width, geometry.canonical_height()),
+                )
+            } else if crop_strip && h > expected {
+                let kept = expected.round() as u32;
+                adjustments.push(Adjustment::Cropped {
+                    bottom: height - kept,
+                });
+                (
+                    image.crop_imm(0, 0, width, kept),
+                    (canonical_width, geometry.canonical_height()),
+                )
+            } else if geometry.answer.is_some() && close(h, grid_height * scale) {
+                anyhow::bail!("Invalid input image: the answer strip is missing");
+            } else {
+                anyhow::bail!(
+                    "Invalid input image size: cannot rescale {width}x{height} to the model layout"
+                );
+            }
+        }
+        // The height gives the scale, the width the number of tiles. Screenshots are not
+        // smaller than the layout, so an image that would need upscaling but matches the
+        // tile row alone is missing its answer strip.
+        None => {
+            let scale = h / canonical_height;
+            let missing_strip = geometry.answer.is_some()
+                && h < canonical_height
+                && columns_at(w * grid_height / h, geometry.tile_width).is_some();
+            if missing_strip {
+                anyhow::bail!("Invalid input image: the answer strip is missing");
+            }
+            match columns_at(w / scale, geometry.tile_width) {
+                Some(columns) => (
+                    image.clone(),
+                    (
+                        geometry.canonical_width(columns),
+                        geometry.canonical_height(),
+                    ),
+                ),
+                None => anyhow::bail!(
+                    "Invalid input image size: cannot rescale {width}x{height} to the model layout"
+                ),
+            }
+        }
+    };
+
+    let from = (image.width(), image.height());
+    let image = if from != target {
+        adjustments.push(Adjustment::Scaled {
+            from,
+            to: target,
+            factor: target.1 as f32 / from.1 as f32,
+        });
+        image.resize_exact(target.0, target.1, FilterType::Lanczos3)
+    } else {
+        image
+    };
+    geometry.layout(image.width(), image.height())?;
+    Ok((image, adjustments))
+}
+
+/// Remove the borders of the top-left pixel color, `None` if there are none
+fn trim_borders(image: &DynamicImage) -> Option<(DynamicImage, Adjustment)> {
+    let rgb = image.to_rgb8();
+    let (width, height) = rgb.dimensions();
+    let border = *rgb.get_pixel(0, 0);
+    let similar = |pixel: &Rgb<u8>| {
+        pixel
+            .0
+            .iter()
+            .zip(border.0)
+            .all(|(&a, b)| a.abs_diff(b) <= BORDER_TOLERANCE)
+    };
+    let uniform_row = |y: u32| (0..width).all(|x| similar(rgb.get_pixel(x, y)));
+    let uniform_column =
+        |x: u32, top: u32, bottom: u32| (top..bottom).all(|y| similar(rgb.get_pixel(x, y)));
+
+    let mut top = 0;
+    while top < height && uniform_row(top) {
+        top += 1;
+    }
+    if top == height {
+        return None;
+    }
+    let mut bottom = height;
+    while bottom > top && uniform_row(bottom - 1) {
+        bottom -= 1;
+    }
+    let mut left = 0;
+    while left < width && uniform_column(left, top, bottom) {
+        left += 1;
+    }
+    let mut right = width;
+    while right > left && uniform_column(right - 1, top, bottom) {
+        right -= 1;
+    }
+    if (left, top, right, bottom) == (0, 0, width, height) {
+        return None;
+    }
+
+    Some((
+        image.crop_imm(left, top, right - left, bottom - top),
+        Adjustment::Trimmed {
+            left,
+            top,
+            right: width - right,
+            bottom: height - bottom,
+        },
+    ))
+}
+
+/// Whether the image has exactly the size the geometry expects, larger images are
+/// accepted by the layout but would be cropped rather than scaled
+fn canonical(geometry: &Geometry, width: u32, height: u32) -> bool {
+    geometry.layout(width, height).is_ok()
+        && height == geometry.canonical_height()
+        && geometry
+            .columns
+            .map_or(width, |columns| geometry.canonical_width(columns))
+            == width
+}
+
+/// Number of tiles of the given width, if the width is close to a whole number of tiles
+fn columns_at(width: f32, tile_width: u32) -> Option<u32> {
+    let columns = (width / tile_width as f32).round();
+    (columns >= 1.0 && close(width, columns * tile_width as f32)).then_some(columns as u32)
+}
+
+fn close(actual: f32, expected: f32) -> bool {
+    (actual - expected).abs() <= (expected * TOLERANCE).max(1.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::descriptor::Descriptor;
+    use image::RgbImage;
+
+    fn pair() -> Geometry {
+        Descriptor::pair_classifier().geometry
+    }
+
+    fn classifier() -> Geometry {
+        Descriptor::classifier().geometry
+    }
+
+    /// Content image with a uniform white border
+    fn padded(width: u32, height: u32, border: u32) -> DynamicImage {
+        let mut image =
+            RgbImage::from_pixel(width + 2 * border, height + 2 * border, Rgb([255; 3]));
+        for y in 0..height {
+            for x in 0..width {
+                image.put_pixel(
+                    x + border,
+                    y + border,
+                    Rgb([(x % 200) as u8, (y % 200) as u8, 40]),
+                );
+            }
+        }
+        DynamicImage::ImageRgb8(image)
+    }
+
+    #[test]
+    fn canonical_images_are_unchanged() {
+        let (image, adjustments) = normalize(DynamicImage::new_rgb8(600, 400), &pair()).unwrap();
+        assert_eq!((image.width(), image.height()), (600, 400));
+        assert!(adjustments.is_empty());
+    }
+
+    #[test]
+    fn rescales_pair_screenshots() {
+        let (image, adjustments) = normalize(DynamicImage::new_rgb8(900, 600), &pair()).unwrap();
+        assert_eq!((image.width(), image.height()), (600, 400));
+        assert_eq!(
+            adjustments,
+            vec![Adjustment::Scaled {
+                from: (900, 600),
+                to: (600, 400),
+                factor: 400.0 / 600.0,
+            }]
+        );
+    }
+
+    #[test]
+    fn rescales_classifier_screenshots() {
+        let (image, _) = normalize(DynamicImage::new_rgb8(450, 300), &classifier()).unwrap();
+        assert_eq!((image.width(), image.height()), (300, 200));
+    }
+
+    #[test]
+    fn trims_uniform_borders() {
+        let (image, adjustments) = normalize(padded(300, 200, 10), &classifier()).unwrap();
+        assert_eq!((image.width(), image.height()), (300, 200));
+        assert_eq!(
+            adjustments,
+            vec![Adjustment::Trimmed {
+                left: 10,
+                top: 10,
+                right: 10,
+                bottom: 10,
+            }]
+        );
+    }
+
+    #[test]
+    fn crops_an_extra_strip_below_the_grid() {
+        let (image, adjustments) =
+            normalize(DynamicImage::new_rgb8(300, 260), &classifier()).unwrap();
+        assert_eq!((image.width(), image.height()), (300, 200));
+        assert!(adjustments.contains(&Adjustment::Cropped { bottom: 60 }));
+    }
+
+    #[test]
+    fn reports_a_missing_answer_strip() {
+        let err = normalize(DynamicImage::new_rgb8(600, 200), &pair()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid input image: the answer strip is missing"
+        );
+    }
+
+    #[test]
+    fn rejects_unrelated_sizes() {
+        assert!(normalize(DynamicImage::new_rgb8(123, 457), &pair()).is_err());
+    }
+}
@@ -0,0 +1,70 @@
+//! INT8 quantized model variants, `<model>_int8.onnx` next to the fp32 model.
+//!
+//! A variant is only loaded with `--prefer-quantized` once `fcsrv models compare --adopt`
+//! has reported its accuracy and recorded it in `<model-dir>/quantized.json`.
+
+use anyhow::{Context, Result};
+use std::{
+    collections::BTreeSet,
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use crate::ModelArgs;
+
+/// Adopted variants, a JSON list of the fp32 model file names
+const ADOPTED_FILE: &str = "quantized.json";
+
+/// File name of the quantized variant, e.g. `shadows_int8.onnx`
+pub fn variant(onnx: &str) -> String {
+    let stem = onnx.strip_suffix(".onnx").unwrap_or(onnx);
+    format!("{stem}_int8.onnx")
+}
+
+/// Models of the directory whose quantized variant is adopted
+pub fn adopted(model_dir: &Path) -> Result<BTreeSet<String>> {
+    let path = model_dir.join(ADOPTED_FILE);
+    match fs::read(&path) {
+        Ok(bytes) => serde_json::from_slice(&bytes)
+            .with_context(|| format!("invalid quantized variant list {}", path.display())),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(BTreeSet::new()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Record the adopted variants of the directory
+pub fn set_adopted(model_dir: &Path, adopted: &BTreeSet<String>) -> Result<()> {
+    fs::write(
+        model_dir.join(ADOPTED_FILE),
+        serde_json::to_vec_pretty(adopted)?,
+    )?;
+    Ok(())
+}
+
+/// Quantized variant to load instead of the fp32 model, if preferred and adopted
+pub fn select(onnx: &str, model_dir: &Path, args: &ModelArgs) -> Result<Option<PathBuf>> {
+    let path = model_dir.join(variant(onnx));
+    if args.force_quantized {
+        if !path.exists() {
+            anyhow::bail!("quantized variant {} not found", path.display());
+        }
+        return Ok(Some(path));
+    }
+    if !args.prefer_quantized {
+        return Ok(None);
+    }
+    if !adopted(model_dir)?.contains(onnx) {
+        tracing::debug!("no adopted quantized variant of {onnx}");
+        return Ok(None);
+    }
+    if !path.exists() {
+        tracing::warn!(
+            "adopted quantized variant {} not found, loading {onnx}",
+            path.display()
+        );
+        return Ok(None);
+    }
+    tracing::info!("loading quantized variant {}", path.display());
+    Ok(Some(path))
+}
@@ -1,5 +1,8 @@
-use super::{base::ImagePairClassifierPredictor, Predictor};
-use crate::BootArgs;
+use super::{
+    base::ImagePairClassifierPredictor, descriptor::Descriptor, Layout, ModelType, Prediction,
+    Predictor,
+};
+use crate::ModelArgs;
 use anyhow::Result;
 use image::DynamicImage;
 
@@ -7,16 +10,28 @@ pub struct TrainCoordinatesPredictor(ImagePairClassifierPredictor);
 
 impl TrainCoordinatesPredictor {
     /// Create a new instance of the TrainCoordinatesPredictor
-    pub fn new(args: &BootArgs) -> Result<Self> {
+    pub fn new(args: &ModelArgs) -> Result<Self> {
         Ok(Self(ImagePairClassifierPredictor::new(
-            "train_coordinates.onnx",
+            ModelType::TrainCoordinates.onnx(),
             args,
         )?))
     }
 }
 
 impl Predictor for TrainCoordinatesPredictor {
-    fn predict(&self, image: DynamicImage) -> Result<i32> {
+    fn predict(&self, image: DynamicImage) -> Result<Prediction> {
         self.0.predict(image)
     }
+
+    fn layout(&self, image: &DynamicImage) -> Result<Layout> {
+        self.0.layout(image)
+    }
+
+    fn descriptor(&self) -> &Descriptor {
+        self.0.descriptor()
+    }
+
+    fn warm_up(&self, image: &DynamicImage) -> Result<()> {
+        self.0.warm_up(image)
+    }
 }
@@ -0,0 +1,280 @@
+//! Quantized model variants: accuracy comparison against the fp32 models and adoption
+
+use anyhow::Result;
+use std::{collections::BTreeMap, path::PathBuf, time::Instant};
+
+use crate::{
+    eval,
+    model::{self, quantized, ModelType, Predictor},
+    CompareArgs, ModelArgs, ModelsArgs, ModelsCommand,
+};
+
+/// Accuracy and latency of one model type, fp32 and quantized
+struct Comparison {
+    model_type: ModelType,
+    images: usize,
+    fp32: Score,
+    int8: Score,
+    /// images both variants answer the same
+    agree: usize,
+}
+
+#[derive(Default)]
+struct Score {
+    correct: usize,
+    total_ms: f64,
+}
+
+pub fn models(args: ModelsArgs) -> Result<()> {
+    match args.command {
+        ModelsCommand::Compare(args) => compare(args),
+    }
+}
+
+/// Run both variants over the labeled corpus, print the comparison and optionally adopt
+/// the variants within the accuracy drop
+fn compare(args: CompareArgs) -> Result<()> {
+    let model_dir = model::model_dir(args.model.model_dir.as_deref());
+    let mut comparisons = Vec::new();
+    for (model_type, dir) in eval::corpus_models(&args.dir, &args.types)? {
+        let variant = quantized::variant(model_type.onnx());
+        if !model_dir.join(&variant).exists() {
+            eprintln!("Skipping {}: no {variant}", model_type.as_str());
+            continue;
+        }
+        let files = eval::corpus_images(&dir)?;
+        comparisons.push(compare_model(model_type, &files, &args.model)?);
+    }
+    if comparisons.is_empty() {
+        anyhow::bail!(
+            "no quantized variants in {}, expected e.g. {}",
+            model_dir.display(),
+            quantized::variant(ModelType::Shadows.onnx())
+        );
+    }
+
+    let within = within(&comparisons, args.max_drop);
+    print_table(&comparisons, &within);
+    for comparison in comparisons.iter().filter(|c| !c.scored()) {
+        println!(
+            "{}: skipped: no scored images",
+            comparison.model_type.as_str()
+        );
+    }
+
+    let mut adopted = quantized::adopted(&model_dir)?;
+    if !args.adopt {
+        println!();
+        println!(
+            "Run with --adopt to adopt the variants within a {:.2}% accuracy drop",
+            args.max_drop * 100.0
+        );
+        return Ok(());
+    }
+    for (&onnx, &ok) in &within {
+        match ok {
+            Some(true) => adopted.insert(onnx.to_owned()),
+            Some(false) => adopted.remove(onnx),
+            // no evidence either way, the adopted set is left as is
+            None => continue,
+        };
+    }
+    quantized::set_adopted(&model_dir, &adopted)?;
+    println!();
+    println!(
+        "Adopted variants: {}",
+        adopted.iter().cloned().collect::<Vec<String>>().join(", ")
+    );
+    Ok(())
+}
+
+fn compare_model(model_type: ModelType, files: &[PathBuf], args: &ModelArgs) -> Result<Comparison> {
+    let fp32 = model::new_predictor(
+        model_type,
+        &ModelArgs {
+            prefer_quantized: false,
+            force_quantized: false,
+            ..args.clone()
+        },
+    )?;
+    let int8 = model::new_predictor(
+        model_type,
+        &ModelArgs {
+            force_quantized: true,
+            ..args.clone()
+        },
+    )?;
+
+    let mut comparison = Comparison {
+        model_type,
+        images: 0,
+        fp32: Score::default(),
+        int8: Score::default(),
+        agree: 0,
+    };
+    for file in files {
+        let (Ok(expected), Ok(image)) = (eval::label(file), image::open(file)) else {
+            eprintln!("Skipping {}: missing label or unreadable", file.display());
+            continue;
+        };
+        comparison.images += 1;
+        let a = run(fp32.as_ref(), image.clone(), expected, &mut comparison.fp32);
+        let b = run(int8.as_ref(), image, expected, &mut comparison.int8);
+        if a.is_some() && a == b {
+            comparison.agree += 1;
+        }
+    }
+    Ok(comparison)
+}
+
+/// Predict and score the image, returns the answer
+fn run(
+    predictor: &dyn Predictor,
+    image: image::DynamicImage,
+    expected: u32,
+    score: &mut Score,
+) -> Option<i32> {
+    let start = Instant::now();
+    let answer = predictor
+        .predict(image)
+        .ok()
+        .map(|prediction| prediction.answer);
+    score.total_ms += start.elapsed().as_secs_f64() * 1000.0;
+    if answer.is_some_and(|answer| i64::from(answer) == i64::from(expected)) {
+        score.correct += 1;
+    }
+    answer
+}
+
+/// Whether the variant of each model file is within the accuracy drop, `None` without
+/// scored images. Types sharing a model file must all be within the drop.
+fn within(comparisons: &[Comparison], max_drop: f64) -> BTreeMap<&'static str, Option<bool>> {
+    let mut within = BTreeMap::<&'static str, Option<bool>>::new();
+    for comparison in comparisons {
+        let ok = comparison.scored().then(|| comparison.drop() <= max_drop);
+        let entry = within
+            .entry(comparison.model_type.onnx())
+            .or_insert(Some(true));
+        *entry = match (*entry, ok) {
+            (Some(false), _) | (_, Some(false)) => Some(false),
+            (None, _) | (_, None) => None,
+            (Some(true), Some(true)) => Some(true),
+        };
+    }
+    within
+}
+
+impl Comparison {
+    /// At least one image was scored and the fp32 model answered one correctly, an empty
+    /// or unreadable corpus is no evidence for the variant
+    fn scored(&self) -> bool {
+        self.images > 0 && self.fp32.correct > 0
+    }
+
+    fn accuracy(&self, score: &Score) -> f64 {
+        eval::ratio(score.correct, self.images)
+    }
+
+    /// Accuracy lost by the quantized variant, negative if it gained
+    fn drop(&self) -> f64 {
+        self.accuracy(&self.fp32) - self.accuracy(&self.int8)
+    }
+
+    fn mean_ms(&self, score: &Score) -> f64 {
+        if self.images == 0 {
+            0.0
+        } else {
+            score.total_ms / self.images as f64
+        }
+    }
+}
+
+fn print_table(comparisons: &[Comparison], within: &BTreeMap<&'static str, Option<bool>>) {
+    println!(
+        "{:<24} {:>7} {:>8} {:>8} {:>7} {:>8} {:>8} {:>8} {:>6}",
+        "MODEL", "IMAGES", "FP32", "INT8", "DROP", "AGREE", "FP32 MS", "INT8 MS", "ADOPT"
+    );
+    for comparison in comparisons {
+        let adopt = match within.get(comparison.model_type.onnx()).copied().flatten() {
+            Some(true) => "yes",
+            Some(false) => "no",
+            None => "-",
+        };
+        println!(
+            "{:<24} {:>7} {:>7.2}% {:>7.2}% {:>6.2}% {:>7.2}% {:>8.2} {:>8.2} {:>6}",
+            comparison.model_type.as_str(),
+            comparison.images,
+            comparison.accuracy(&comparison.fp32) * 100.0,
+            comparison.accuracy(&comparison.int8) * 100.0,
+            comparison.drop() * 100.0,
+            eval::ratio(comparison.agree, comparison.images) * 100.0,
+            comparison.mean_ms(&comparison.fp32),
+            comparison.mean_ms(&comparison.int8),
+            adopt
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn comparison(model_type: ModelType, images: usize, fp32: usize, int8: usize) -> Comparison {
+        Comparison {
+            model_type,
+            images,
+            fp32: Score {
+                correct: fp32,
+                total_ms: 0.0,
+            },
+            int8: Score {
+                correct: int8,
+                total_ms: 0.0,
+            },
+            agree: 0,
+        }
+    }
+
+    #[test]
+    fn empty_corpus_is_not_adopted() {
+        let within = within(&[comparison(ModelType::Shadows, 0, 0, 0)], 0.01);
+        assert_eq!(within[ModelType::Shadows.onnx()], None);
+    }
+
+    #[test]
+    fn failed_images_are_not_adopted() {
+        let within = within(&[comparison(ModelType::Shadows, 20, 0, 0)], 0.01);
+        assert_eq!(within[ModelType::Shadows.onnx()], None);
+    }
+
+    #[test]
+    fn variants_within_the_drop() {
+        let within = within(
+            &[
+                comparison(ModelType::Shadows, 100, 90, 90),
+                comparison(ModelType::Penguin, 100, 90, 80),
+            ],
+            0.01,
+        );
+        assert_eq!(within[ModelType::Shadows.onnx()], Some(true));
+        assert_eq!(within[ModelType::Penguin.onnx()], Some(false));
+    }
+
+    #[test]
+    fn shared_model_files_need_every_type() {
+        let onnx = ModelType::M3dRollballObjects.onnx();
+        let animals = comparison(ModelType::M3dRollballAnimals, 100, 90, 90);
+        let within_drop = within(
+            &[animals, comparison(ModelType::M3dRollballObjects, 0, 0, 0)],
+            0.01,
+        );
+        assert_eq!(within_drop[onnx], None);
+
+        let animals = comparison(ModelType::M3dRollballAnimals, 100, 90, 50);
+        let within_drop = within(
+            &[animals, comparison(ModelType::M3dRollballObjects, 0, 0, 0)],
+            0.01,
+        );
+        assert_eq!(within_drop[onnx], Some(false));
+    }
+}
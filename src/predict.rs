@@ -0,0 +1,114 @@
+//! Offline single-shot prediction
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::{
+    fs,
+    io::{self, Read},
+    path::Path,
+    time::Instant,
+};
+
+use crate::{decode, model, model::normalize::Adjustment, PredictArgs};
+
+/// Input name read from stdin
+const STDIN: &str = "-";
+
+#[derive(Debug, Serialize)]
+struct Output<'a> {
+    input: &'a str,
+    answer: i32,
+    confidence: f32,
+    scores: &'a [f32],
+    #[serde(skip_serializing_if = "<[Adjustment]>::is_empty")]
+    adjustments: &'a [Adjustment],
+    decode_ms: f64,
+    predict_ms: f64,
+}
+
+/// Load the model and predict every input, an image file, base64 text or `-` for stdin
+pub fn predict(args: PredictArgs) -> Result<()> {
+    let start = Instant::now();
+    model::init_model(args.typed, &args.model)?;
+    let predictor = model::get_predictor(args.typed)?;
+    let load_ms = start.elapsed().as_secs_f64() * 1000.0;
+    if !args.json {
+        println!("{}: loaded in {load_ms:.1}ms", args.typed.as_str());
+    }
+
+    for input in &args.inputs {
+        let bytes = if input == STDIN {
+            let mut bytes = Vec::new();
+            io::stdin().read_to_end(&mut bytes)?;
+            bytes
+        } else {
+            fs::read(input).with_context(|| format!("failed to read {input}"))?
+        };
+
+        let start = Instant::now();
+        let image = decode::decode_bytes(&bytes, args.background)
+            .with_context(|| format!("failed to decode {input}"))?;
+        let decode_ms = start.elapsed().as_secs_f64() * 1000.0;
+        let (width, height) = (image.width(), image.height());
+        let (image, adjustments) = if args.normalize_input {
+            model::normalize::normalize(image, &predictor.descriptor().geometry)?
+        } else {
+            (image, Vec::new())
+        };
+
+        // The predictor consumes the image, keep a copy for the debug image
+        let kept = args.debug_image.is_some().then(|| image.clone());
+
+        let start = Instant::now();
+        let prediction = predictor.predict(image)?;
+        let predict_ms = start.elapsed().as_secs_f64() * 1000.0;
+
+        if let (Some(dir), Some(image)) = (args.debug_image.as_ref(), kept) {
+            let name = match input.as_str() {
+                STDIN => "stdin".to_owned(),
+                input => Path::new(input)
+                    .file_stem()
+                    .map(|stem| stem.to_string_lossy().into_owned())
+                    .unwrap_or_default(),
+            };
+            let path = dir.join(format!("{name}_debug.png"));
+            let rendered = model::debug::render(
+                &image,
+                &predictor.layout(&image)?,
+                predictor.descriptor().preprocess.resize,
+                &prediction,
+            );
+            fs::create_dir_all(dir)?;
+            fs::write(&path, model::debug::encode_png(rendered)?)?;
+            eprintln!("{input}: debug image written to {}", path.display());
+        }
+
+        if args.json {
+            println!(
+                "{}",
+                serde_json::to_string(&Output {
+                    input,
+                    answer: prediction.answer,
+                    confidence: prediction.confidence(),
+                    scores: &prediction.scores,
+                    adjustments: &adjustments,
+                    decode_ms,
+                    predict_ms,
+                })?
+            );
+        } else {
+            println!(
+                "{input}: answer {} (confidence {:.3})",
+                prediction.answer,
+                prediction.confidence()
+            );
+            println!("  image: {width}x{height}");
+            println!("  scores: {:?}", prediction.scores);
+            if !adjustments.is_empty() {
+                println!("  adjustments: {adjustments:?}");
+            }
+            println!("  decode {decode_ms:.1}ms, predict {predict_ms:.1}ms");
+        }
+    }
+    Ok(())
+}
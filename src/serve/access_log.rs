@@ -0,0 +1,165 @@
+use anyhow::Result;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::{fs::OpenOptions, io::Write, net::IpAddr, path::Path, sync::Mutex, time::SystemTime};
+use tracing_appender::non_blocking::{NonBlocking, NonBlockingBuilder, WorkerGuard};
+
+/// Access log line format
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default)]
+pub enum AccessLogFormat {
+    /// One JSON object per line
+    #[default]
+    Json,
+    /// Space separated `key=value` line
+    Cli,
+}
+
+/// Summary of a single `/task` call
+#[derive(Debug, Serialize)]
+pub struct AccessEntry<'a> {
+    /// RFC 3339 timestamp
+    pub timestamp: String,
+    pub request_id: &'a str,
+    pub client_ip: Option<IpAddr>,
+    /// API key name, never the key itself
+    pub key: Option<String>,
+    pub model: &'a str,
+    pub images: usize,
+    pub answers: &'a [u32],
+    /// softmax confidence of every answer
+    pub confidence: &'a [f32],
+    pub latency_ms: f64,
+    pub status: u16,
+}
+
+/// Append-only access log file, written by a background thread
+#[derive(Debug)]
+pub struct AccessLog {
+    format: AccessLogFormat,
+    writer: NonBlocking,
+    /// flushes the pending lines when dropped
+    guard: Mutex<Option<WorkerGuard>>,
+}
+
+impl AccessLog {
+    /// Open the access log file for appending
+    pub fn new(path: &Path, format: AccessLogFormat) -> Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        // Block rather than drop lines when the writer falls behind
+        let (writer, guard) = NonBlockingBuilder::default().lossy(false).finish(file);
+        Ok(Self {
+            format,
+            writer,
+            guard: Mutex::new(Some(guard)),
+        })
+    }
+
+    /// Write the pending lines and stop the writer, on shutdown
+    pub fn close(&self) {
+        self.guard.lock().unwrap_or_else(|e| e.into_inner()).take();
+    }
+
+    /// Write the entry as a single line
+    pub fn write(&self, entry: &AccessEntry) {
+        let line = match self.format {
+            AccessLogFormat::Json => match serde_json::to_string(entry) {
+                Ok(line) => line,
+                Err(err) => {
+                    tracing::warn!("failed to serialize access log entry: {err}");
+                    return;
+                }
+            },
+            AccessLogFormat::Cli => format!(
+                "{} {} key={} model={} images={} answers={:?} confidence={:?} latency={:.3}ms status={} request_id={}",
+                entry.timestamp,
+                entry
+                    .client_ip
+                    .map(|ip| ip.to_string())
+                    .unwrap_or_else(|| "-".to_owned()),
+                entry.key.as_deref().unwrap_or("-"),
+                entry.model,
+                entry.images,
+                entry.answers,
+                entry.confidence,
+                entry.latency_ms,
+                entry.status,
+                entry.request_id,
+            ),
+        };
+
+        // A single write per line, so concurrent lines do not interleave
+        if let Err(err) = self
+            .writer
+            .clone()
+            .write_all(format!("{line}\n").as_bytes())
+        {
+            tracing::warn!("failed to write access log: {err}");
+        }
+    }
+}
+
+/// Name of an API key: the first 8 hex digits of its SHA-256
+pub fn key_name(api_key: &str) -> String {
+    let digest = Sha256::digest(api_key.as_bytes());
+    format!("{:x}", digest)[..8].to_owned()
+}
+
+/// Current time as an RFC 3339 timestamp
+pub fn timestamp() -> String {
+    humantime::format_rfc3339_millis(SystemTime::now()).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry() -> AccessEntry<'static> {
+        AccessEntry {
+            timestamp: "2024-01-01T00:00:00.000Z".to_owned(),
+            request_id: "abc",
+            client_ip: Some("127.0.0.1".parse().unwrap()),
+            key: Some(key_name("secret")),
+            model: "shadows",
+            images: 1,
+            answers: &[3],
+            confidence: &[0.5],
+            latency_ms: 12.5,
+            status: 200,
+        }
+    }
+
+    #[test]
+    fn key_names_hide_the_key() {
+        let name = key_name("secret");
+        assert_eq!(name.len(), 8);
+        assert!(name.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_eq!(name, key_name("secret"));
+        assert_ne!(name, key_name("other"));
+    }
+
+    #[test]
+    fn writes_one_line_per_entry() {
+        let dir = tempfile::tempdir().unwrap();
+        let json = dir.path().join("access.json");
+        let cli = dir.path().join("access.log");
+        for (path, format) in [(&json, AccessLogFormat::Json), (&cli, AccessLogFormat::Cli)] {
+            let log = AccessLog::new(path, format).unwrap();
+            log.write(&entry());
+            log.write(&entry());
+            log.close();
+        }
+
+        let json = std::fs::read_to_string(json).unwrap();
+        assert_eq!(json.lines().count(), 2);
+        let line: serde_json::Value = serde_json::from_str(json.lines().next().unwrap()).unwrap();
+        assert_eq!(line["request_id"], "abc");
+        assert_eq!(line["answers"], serde_json::json!([3]));
+        assert_eq!(line["key"], key_name("secret"));
+
+        let cli = std::fs::read_to_string(cli).unwrap();
+        assert_eq!(cli.lines().count(), 2);
+        assert!(cli.starts_with("2024-01-01T00:00:00.000Z 127.0.0.1 key="));
+        assert!(cli.contains(" model=shadows images=1 answers=[3] "));
+        assert!(cli.trim_end().ends_with("status=200 request_id=abc"));
+    }
+}
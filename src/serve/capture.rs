@@ -0,0 +1,397 @@
+use anyhow::Result;
+use image::{DynamicImage, ImageOutputFormat};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::BTreeMap,
+    fs::{self, OpenOptions},
+    io::{Cursor, ErrorKind, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use super::access_log;
+use crate::{
+    cache::CacheKey,
+    metrics::{Accuracy, METRICS},
+    model::{ModelType, Prediction},
+};
+
+/// Directory of the request manifests, looked up by feedback
+const REQUESTS_DIR: &str = "requests";
+/// Persisted feedback counters of every model
+const ACCURACY_FILE: &str = "accuracy.json";
+
+/// Sidecar of a captured image, next to the `.jpg` and `.txt` label
+#[derive(Debug, Serialize)]
+struct Sample<'a> {
+    request_id: &'a str,
+    model: &'a str,
+    answer: i32,
+    scores: &'a [f32],
+    confidence: f32,
+    timestamp: String,
+}
+
+/// Images and answers of a solved request, `requests/<sha256 of the request ID>.json`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Manifest {
+    pub request_id: String,
+    pub model: String,
+    /// image hash and predicted answer, in request order
+    pub images: Vec<(String, i32)>,
+    /// true answers, once reported
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub feedback: Option<Vec<u32>>,
+}
+
+/// Applied feedback of a request, per image
+#[derive(Debug)]
+pub struct Applied {
+    /// whether each prediction was correct
+    pub correct: Vec<bool>,
+    /// whether each image was captured, and so relabeled if the prediction was wrong
+    pub captured: Vec<bool>,
+}
+
+/// Why feedback was not applied
+#[derive(Debug)]
+pub enum FeedbackError {
+    /// the request was never recorded
+    Unknown,
+    /// feedback of the request was already applied
+    Reported,
+    /// the number of answers does not match the images
+    Answers {
+        expected: usize,
+        got: usize,
+    },
+    Internal(anyhow::Error),
+}
+
+impl<E: Into<anyhow::Error>> From<E> for FeedbackError {
+    fn from(err: E) -> Self {
+        Self::Internal(err.into())
+    }
+}
+
+/// Captures decoded images and predictions in the `images/<type>/<hash>_<answer>.jpg` corpus layout
+#[derive(Debug)]
+pub struct Capture {
+    dir: PathBuf,
+    rate: f64,
+    quota: u64,
+    max_confidence: Option<f32>,
+    used: AtomicU64,
+    /// serializes feedback, which rewrites manifests and labels
+    feedback: Mutex<()>,
+}
+
+impl Capture {
+    /// Create the capture directory, `quota` is in bytes
+    pub fn new(dir: &Path, rate: f64, quota: u64, max_confidence: Option<f32>) -> Result<Self> {
+        fs::create_dir_all(dir)?;
+        let used = dir_size(dir)?;
+        if used >= quota {
+            tracing::warn!(
+                "capture directory {} is over its quota ({used} bytes)",
+                dir.display()
+            );
+        }
+
+        // Restore the feedback counters
+        match fs::read(dir.join(ACCURACY_FILE)) {
+            Ok(bytes) => {
+                let counters: BTreeMap<String, Accuracy> = serde_json::from_slice(&bytes)?;
+                for (model, accuracy) in counters {
+                    if let Ok(model) = model.parse::<ModelType>() {
+                        METRICS.record_feedback(model.as_str(), accuracy.total, accuracy.correct);
+                    }
+                }
+            }
+            Err(err) if err.kind() == ErrorKind::NotFound => {}
+            Err(err) => return Err(err.into()),
+        }
+
+        Ok(Self {
+            dir: dir.to_owned(),
+            rate,
+            quota,
+            max_confidence,
+            used: AtomicU64::new(used),
+            feedback: Mutex::new(()),
+        })
+    }
+
+    /// Whether the image is sampled, decided by its hash so retries are consistent
+    pub fn sampled(&self, key: &CacheKey) -> bool {
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(&key.digest[..8]);
+        (u64::from_be_bytes(bytes) as f64 / u64::MAX as f64) < self.rate
+    }
+
+    /// Save the image and its prediction, skipping confident answers and full disks
+    pub fn save(
+        &self,
+        request_id: &str,
+        key: &CacheKey,
+        image: &DynamicImage,
+        prediction: &Prediction,
+    ) -> Result<()> {
+        let confidence = prediction.confidence();
+        if matches!(self.max_confidence, Some(max) if confidence > max) {
+            return Ok(());
+        }
+
+        let dir = self.dir.join(key.model.as_str());
+        let path = dir.join(format!("{}_{}", key.hex(), prediction.answer));
+        if path.with_extension("jpg").exists() {
+            return Ok(());
+        }
+
+        let mut jpg = Cursor::new(Vec::new());
+        DynamicImage::ImageRgb8(image.to_rgb8()).write_to(&mut jpg, ImageOutputFormat::Jpeg(95))?;
+        let jpg = jpg.into_inner();
+        let txt = prediction.answer.to_string();
+        let json = serde_json::to_vec_pretty(&Sample {
+            request_id,
+            model: key.model.as_str(),
+            answer: prediction.answer,
+            scores: &prediction.scores,
+            confidence,
+            timestamp: access_log::timestamp(),
+        })?;
+
+        let size = (jpg.len() + txt.len() + json.len()) as u64;
+        if self.used.fetch_add(size, Ordering::Relaxed) + size > self.quota {
+            self.used.fetch_sub(size, Ordering::Relaxed);
+            tracing::debug!("capture quota exceeded, skipping {}", path.display());
+            return Ok(());
+        }
+
+        fs::create_dir_all(&dir)?;
+        fs::write(path.with_extension("jpg"), jpg)?;
+        fs::write(path.with_extension("txt"), txt)?;
+        fs::write(path.with_extension("json"), json)?;
+        tracing::debug!("captured {}", path.display());
+        Ok(())
+    }
+
+    /// Record the images and answers of a request, so feedback can find them
+    pub fn save_request(
+        &self,
+        request_id: &str,
+        model: ModelType,
+        images: &[(CacheKey, i32)],
+    ) -> Result<()> {
+        let manifest = Manifest {
+            request_id: request_id.to_owned(),
+            model: model.as_str().to_owned(),
+            images: images
+                .iter()
+                .map(|(key, answer)| (key.hex(), *answer))
+                .collect(),
+            feedback: None,
+        };
+        let json = serde_json::to_vec(&manifest)?;
+
+        let size = json.len() as u64;
+        if self.used.fetch_add(size, Ordering::Relaxed) + size > self.quota {
+            self.used.fetch_sub(size, Ordering::Relaxed);
+            tracing::debug!("capture quota exceeded, skipping request manifest");
+            return Ok(());
+        }
+
+        // A reused request ID keeps the first manifest, rather than taking over its feedback
+        let path = self.manifest_path(request_id);
+        fs::create_dir_all(self.dir.join(REQUESTS_DIR))?;
+        let mut file = match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == ErrorKind::AlreadyExists => {
+                self.used.fetch_sub(size, Ordering::Relaxed);
+                tracing::warn!(
+                    "request ID {request_id} was already recorded, not recording it again"
+                );
+                return Ok(());
+            }
+            Err(err) => return Err(err.into()),
+        };
+        file.write_all(&json)?;
+        Ok(())
+    }
+
+    /// Load the manifest of a request, `None` if it was never recorded
+    fn load_request(&self, request_id: &str) -> Result<Option<Manifest>> {
+        match fs::read(self.manifest_path(request_id)) {
+            Ok(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Apply the true answers of a request: relabel the captured images and
+    /// count the correct predictions. Images skipped by sampling, `--capture-max-confidence`
+    /// or the quota are only counted.
+    pub fn feedback(&self, request_id: &str, answers: Vec<u32>) -> Result<Applied, FeedbackError> {
+        // The manifest is checked under the lock, so concurrent reports apply once
+        let _lock = self.feedback.lock().unwrap_or_else(|e| e.into_inner());
+        let mut manifest = self
+            .load_request(request_id)?
+            .ok_or(FeedbackError::Unknown)?;
+        if manifest.feedback.is_some() {
+            return Err(FeedbackError::Reported);
+        }
+        if answers.len() != manifest.images.len() {
+            return Err(FeedbackError::Answers {
+                expected: manifest.images.len(),
+                got: answers.len(),
+            });
+        }
+        let model: ModelType = manifest.model.parse()?;
+
+        let dir = self.dir.join(model.as_str());
+        let mut correct = Vec::with_capacity(answers.len());
+        let mut captured = Vec::with_capacity(answers.len());
+        for ((hash, predicted), &answer) in manifest.images.iter().zip(&answers) {
+            let hit = i64::from(*predicted) == i64::from(answer);
+            captured.push(if hit {
+                dir.join(format!("{hash}_{predicted}.jpg")).exists()
+            } else {
+                relabel(&dir, hash, *predicted, answer)?
+            });
+            correct.push(hit);
+        }
+
+        let hits = correct.iter().filter(|&&correct| correct).count() as u64;
+        METRICS.record_feedback(model.as_str(), correct.len() as u64, hits);
+        let counters = METRICS
+            .accuracy()
+            .into_iter()
+            .map(|(model, accuracy)| (model.to_owned(), accuracy))
+            .collect::<BTreeMap<String, Accuracy>>();
+        fs::write(
+            self.dir.join(ACCURACY_FILE),
+            serde_json::to_vec_pretty(&counters)?,
+        )?;
+
+        let path = self.manifest_path(&manifest.request_id);
+        manifest.feedback = Some(answers);
+        fs::write(path, serde_json::to_vec(&manifest)?)?;
+        Ok(Applied { correct, captured })
+    }
+
+    /// Request IDs are client supplied, hash them into a safe file name
+    fn manifest_path(&self, request_id: &str) -> PathBuf {
+        let digest = Sha256::digest(request_id.as_bytes());
+        self.dir
+            .join(REQUESTS_DIR)
+            .join(format!("{:x}.json", digest))
+    }
+}
+
+/// Move a captured image from its predicted label to the true one, returns false if the
+/// image was not captured
+fn relabel(dir: &Path, hash: &str, predicted: i32, answer: u32) -> Result<bool> {
+    let from = dir.join(format!("{hash}_{predicted}"));
+    if !from.with_extension("jpg").exists() {
+        return Ok(false);
+    }
+
+    let to = dir.join(format!("{hash}_{answer}"));
+    if to.with_extension("jpg").exists() {
+        // already labeled by an earlier report of the same image
+        for extension in ["jpg", "txt", "json"] {
+            let _ = fs::remove_file(from.with_extension(extension));
+        }
+        return Ok(true);
+    }
+
+    fs::rename(from.with_extension("jpg"), to.with_extension("jpg"))?;
+    let _ = fs::rename(from.with_extension("json"), to.with_extension("json"));
+    let _ = fs::remove_file(from.with_extension("txt"));
+    fs::write(to.with_extension("txt"), answer.to_string())?;
+    tracing::debug!("relabeled {} as {answer}", from.display());
+    Ok(true)
+}
+
+/// Total size of the files in the directory tree
+fn dir_size(dir: &Path) -> Result<u64> {
+    let mut size = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            size += dir_size(&entry.path())?;
+        } else {
+            size += metadata.len();
+        }
+    }
+    Ok(size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::RgbImage;
+
+    fn key(i: u8) -> CacheKey {
+        CacheKey {
+            model: ModelType::Shadows,
+            digest: [i; 32],
+        }
+    }
+
+    #[test]
+    fn feedback_reports_uncaptured_images() {
+        let dir = tempfile::tempdir().unwrap();
+        let capture = Capture::new(dir.path(), 1.0, u64::MAX, Some(0.6)).unwrap();
+        let image = DynamicImage::ImageRgb8(RgbImage::new(4, 4));
+
+        // a confident answer is not captured, an uncertain one is
+        let confident = Prediction::from_scores(vec![10.0, 0.0]);
+        let uncertain = Prediction::from_scores(vec![0.1, 0.0]);
+        capture.save("a", &key(0), &image, &confident).unwrap();
+        capture.save("a", &key(1), &image, &uncertain).unwrap();
+        capture
+            .save_request("a", ModelType::Shadows, &[(key(0), 0), (key(1), 0)])
+            .unwrap();
+
+        let applied = capture.feedback("a", vec![1, 1]).unwrap();
+        assert_eq!(applied.correct, [false, false]);
+        assert_eq!(applied.captured, [false, true]);
+        let relabeled = dir
+            .path()
+            .join("shadows")
+            .join(format!("{}_1.jpg", key(1).hex()));
+        assert!(relabeled.exists());
+    }
+
+    #[test]
+    fn feedback_applies_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let capture = Capture::new(dir.path(), 1.0, u64::MAX, None).unwrap();
+        capture
+            .save_request("b", ModelType::Shadows, &[(key(2), 1)])
+            .unwrap();
+
+        assert!(matches!(
+            capture.feedback("unknown", vec![1]),
+            Err(FeedbackError::Unknown)
+        ));
+        assert!(matches!(
+            capture.feedback("b", vec![1, 2]),
+            Err(FeedbackError::Answers {
+                expected: 1,
+                got: 2
+            })
+        ));
+        assert_eq!(capture.feedback("b", vec![1]).unwrap().correct, [true]);
+        assert!(matches!(
+            capture.feedback("b", vec![1]),
+            Err(FeedbackError::Reported)
+        ));
+    }
+}
@@ -1,21 +1,57 @@
+mod access_log;
+mod capture;
+mod pool;
 mod task;
 
-use std::convert::Infallible;
+use std::{
+    convert::Infallible,
+    future::Future,
+    net::SocketAddr,
+    pin::Pin,
+    time::{Duration, Instant},
+};
 
-use self::task::{Task, TaskResult};
-use crate::{model, BootArgs};
+pub use self::access_log::AccessLogFormat;
+use self::access_log::{AccessEntry, AccessLog};
+use self::capture::{Capture, FeedbackError};
+use self::pool::{Cancellation, InferencePool, PoolError};
+use self::task::{Feedback, FeedbackResult, ModelInfo, Task, TaskResult};
+use crate::{
+    cache::{disk::DiskCache, AnswerCache, CacheKey, CACHE_DIR},
+    decode,
+    metrics::METRICS,
+    model::{self, normalize::Adjustment, ModelType, Prediction, Predictor},
+    BootArgs,
+};
 use anyhow::Result;
-use image::DynamicImage;
+use image::{DynamicImage, Rgb};
 use rayon::iter::{IndexedParallelIterator, IntoParallelIterator, ParallelIterator};
 use reqwest::StatusCode;
 use tokio::sync::OnceCell;
+use tracing::Instrument;
 use warp::filters::body::BodyDeserializeError;
+use warp::http::HeaderValue;
 use warp::reject::{Reject, Rejection};
 use warp::reply::Reply;
 use warp::Filter;
 
+/// Request ID header, echoed back in every response
+const REQUEST_ID_HEADER: &str = "x-request-id";
+/// Longest client supplied request ID that is accepted as-is
+const REQUEST_ID_MAX_LEN: usize = 128;
+/// Answer cache status header, `HIT`, `MISS` or `PARTIAL` for multiple images
+const CACHE_HEADER: &str = "x-cache";
+
 static API_KEY: OnceCell<Option<String>> = OnceCell::const_new();
 static SUBMIT_LIMIT: OnceCell<Option<usize>> = OnceCell::const_new();
+static NORMALIZE_INPUT: OnceCell<bool> = OnceCell::const_new();
+static BACKGROUND: OnceCell<Rgb<u8>> = OnceCell::const_new();
+static ACCESS_LOG: OnceCell<Option<AccessLog>> = OnceCell::const_new();
+static ANSWER_CACHE: OnceCell<Option<AnswerCache>> = OnceCell::const_new();
+static CAPTURE: OnceCell<Option<Capture>> = OnceCell::const_new();
+static INFERENCE_POOL: OnceCell<InferencePool> = OnceCell::const_new();
+/// Set once every model is loaded and warmed up
+static READY: OnceCell<()> = OnceCell::const_new();
 
 pub struct Serve(BootArgs);
 
@@ -32,96 +68,544 @@ impl Serve {
         // Init submit limit
         SUBMIT_LIMIT.set(Some(self.0.multi_image_limit))?;
 
+        // Init input normalization
+        NORMALIZE_INPUT.set(self.0.normalize_input)?;
+
+        // Init transparent image background
+        BACKGROUND.set(self.0.background)?;
+
+        // Init access log
+        ACCESS_LOG.set(match self.0.access_log.as_ref() {
+            Some(path) => Some(AccessLog::new(path, self.0.access_log_format)?),
+            None => None,
+        })?;
+
+        // Init answer cache
+        let disk = if self.0.cache_persist && self.0.cache_size > 0 {
+            let path = model::model_dir(self.0.model.model_dir.as_deref()).join(CACHE_DIR);
+            Some(DiskCache::open(&path, self.0.cache_disk_size)?)
+        } else {
+            None
+        };
+        ANSWER_CACHE.set(AnswerCache::new(
+            self.0.cache_size,
+            Duration::from_secs(self.0.cache_ttl),
+            disk,
+        ))?;
+
+        // Init sample capture
+        CAPTURE.set(match self.0.capture_dir.as_ref() {
+            Some(dir) => Some(Capture::new(
+                dir,
+                self.0.capture_rate,
+                self.0.capture_quota * 1024 * 1024,
+                self.0.capture_max_confidence,
+            )?),
+            None => None,
+        })?;
+
+        // Init inference pool
+        if INFERENCE_POOL
+            .set(InferencePool::new(
+                self.0.inference_threads,
+                self.0.inference_queue,
+            )?)
+            .is_err()
+        {
+            anyhow::bail!("inference pool is already initialized");
+        }
+
         // Init routes
-        let routes = warp::path("task")
+        let task = warp::path!("task")
+            .and(warp::post())
+            .and(request_id())
+            .and(warp::addr::remote())
+            .and(task_body())
+            .and_then(handle_task);
+        let feedback = warp::path!("task" / String / "feedback")
             .and(warp::post())
             .and(warp::body::json())
-            .and_then(handle_task)
-            .recover(handle_rejection)
+            .and_then(handle_feedback);
+        let models = warp::path("models").and(warp::get()).map(handle_models);
+        let ready = warp::path("ready").and(warp::get()).map(handle_ready);
+        let metrics = warp::path("metrics")
+            .and(warp::get())
+            .map(|| METRICS.render());
+        let routes = with_request_id(task.or(feedback).or(models).or(ready).or(metrics))
             .with(warp::trace::request());
 
         tracing::info!("Listening on {}", self.0.bind);
 
         // Start the server
-        match (self.0.tls_cert, self.0.tls_key) {
-            (Some(cert), Some(key)) => {
-                warp::serve(routes)
-                    .tls()
-                    .cert_path(cert)
-                    .key_path(key)
-                    .bind_with_graceful_shutdown(self.0.bind, async {
-                        tokio::signal::ctrl_c()
-                            .await
-                            .expect("failed to install CTRL+C signal handler");
-                    })
-                    .1
-                    .await;
-            }
-            _ => {
-                warp::serve(routes)
-                    .bind_with_graceful_shutdown(self.0.bind, async {
-                        tokio::signal::ctrl_c()
-                            .await
-                            .expect("failed to install CTRL+C signal handler");
-                    })
-                    .1
-                    .await;
+        let mut server: Pin<Box<dyn Future<Output = ()> + Send>> =
+            match (self.0.tls_cert, self.0.tls_key) {
+                (Some(cert), Some(key)) => Box::pin(
+                    warp::serve(routes)
+                        .tls()
+                        .cert_path(cert)
+                        .key_path(key)
+                        .bind_with_graceful_shutdown(self.0.bind, async {
+                            tokio::signal::ctrl_c()
+                                .await
+                                .expect("failed to install CTRL+C signal handler");
+                        })
+                        .1,
+                ),
+                _ => Box::pin(
+                    warp::serve(routes)
+                        .bind_with_graceful_shutdown(self.0.bind, async {
+                            tokio::signal::ctrl_c()
+                                .await
+                                .expect("failed to install CTRL+C signal handler");
+                        })
+                        .1,
+                ),
+            };
+
+        // Load and warm up the models while listening, startup fails if any cannot run
+        let model_args = self.0.model;
+        let loading = tokio::task::spawn_blocking(move || model::init_predictor(&model_args));
+        let loaded = tokio::select! {
+            _ = &mut server => None,
+            loaded = loading => Some(loaded.map_err(anyhow::Error::from).and_then(|loaded| loaded)),
+        };
+        let result = match loaded {
+            // stopped before the models were loaded
+            None => Ok(()),
+            Some(Err(err)) => Err(err),
+            Some(Ok(())) => {
+                let _ = READY.set(());
+                tracing::info!("Models are ready");
+                server.await;
+                Ok(())
             }
+        };
+
+        // Write the pending access log lines, also when stopped during loading
+        if let Some(Some(access_log)) = ACCESS_LOG.get() {
+            access_log.close();
         }
-        Ok(())
+        result
     }
 }
 
+/// Deserialize the task, passing invalid bodies on to the handler so they are logged
+fn task_body() -> impl Filter<Extract = (Result<Task, Rejection>,), Error = Infallible> + Clone {
+    warp::body::json()
+        .map(Ok)
+        .or_else(|err| async move { Ok::<_, Infallible>((Err(err),)) })
+}
+
+/// Extract the request ID from the `X-Request-Id` header, or generate one
+fn request_id() -> impl Filter<Extract = (String,), Error = Rejection> + Clone {
+    warp::header::optional::<String>(REQUEST_ID_HEADER).map(assign_request_id)
+}
+
+/// The client supplied request ID if acceptable, or a new one
+fn assign_request_id(id: Option<String>) -> String {
+    id.filter(|id| !id.is_empty() && id.len() <= REQUEST_ID_MAX_LEN)
+        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string())
+}
+
+/// Answer the rejections of the routes, and echo the request ID on every response.
+/// Routes that assign the ID themselves, like `/task`, keep theirs.
+fn with_request_id<F, R>(
+    routes: F,
+) -> impl Filter<Extract = (warp::reply::Response,), Error = Infallible> + Clone
+where
+    F: Filter<Extract = (R,), Error = Rejection> + Clone + Send + Sync + 'static,
+    R: Reply,
+{
+    // read leniently, a malformed header gets a new ID rather than a rejection
+    warp::header::headers_cloned()
+        .map(|headers: warp::http::HeaderMap| {
+            headers
+                .get(REQUEST_ID_HEADER)
+                .and_then(|id| id.to_str().ok())
+                .map(str::to_owned)
+        })
+        .and(
+            routes
+                .map(|reply: R| Ok(reply.into_response()))
+                .or_else(|err| async move { Ok::<_, Infallible>((Err(err),)) }),
+        )
+        .map(
+            |id: Option<String>, response: Result<warp::reply::Response, Rejection>| {
+                let mut response = match response {
+                    Ok(response) => response,
+                    Err(err) => handle_rejection(&err, assign_request_id(id.clone())),
+                };
+                let headers = response.headers_mut();
+                if !headers.contains_key(REQUEST_ID_HEADER) {
+                    if let Ok(value) = HeaderValue::from_str(&assign_request_id(id)) {
+                        headers.insert(REQUEST_ID_HEADER, value);
+                    }
+                }
+                response
+            },
+        )
+}
+
 /// Handle the task
-async fn handle_task(task: Task) -> Result<impl Reply, Rejection> {
+async fn handle_task(
+    request_id: String,
+    remote: Option<SocketAddr>,
+    task: Result<Task, Rejection>,
+) -> Result<impl Reply, Infallible> {
+    let start = Instant::now();
+    let (model, images, key) = match task.as_ref() {
+        Ok(task) => (
+            task.typed.as_str(),
+            task.images.len(),
+            task.api_key.as_deref().map(access_log::key_name),
+        ),
+        Err(_) => ("-", 0, None),
+    };
+    let span = tracing::info_span!("task", request_id = %request_id, model, images);
+
+    let solved = match task {
+        Ok(task) => solve_task(&request_id, task).instrument(span).await,
+        Err(err) => Err(err),
+    };
+    let mut cache_hits = 0;
+    let (result, confidence, code) = match solved {
+        Ok(solved) => {
+            cache_hits = solved.iter().filter(|solved| solved.cached).count();
+            let debug_images = solved
+                .iter()
+                .map(|solved| solved.debug_image.clone())
+                .collect::<Option<Vec<String>>>();
+            let adjustments = solved
+                .iter()
+                .any(|solved| !solved.adjustments.is_empty())
+                .then(|| {
+                    solved
+                        .iter()
+                        .map(|solved| solved.adjustments.clone())
+                        .collect::<Vec<Vec<Adjustment>>>()
+                });
+            (
+                TaskResult {
+                    request_id: Some(request_id.clone()),
+                    error: None,
+                    solve: true,
+                    objects: solved
+                        .iter()
+                        .map(|solved| solved.prediction.answer as u32)
+                        .collect(),
+                    debug_images,
+                    adjustments,
+                },
+                solved
+                    .iter()
+                    .map(|solved| solved.prediction.confidence())
+                    .collect::<Vec<f32>>(),
+                StatusCode::OK,
+            )
+        }
+        Err(err) => {
+            let (code, message) = rejection_message(&err);
+            (
+                TaskResult {
+                    request_id: Some(request_id.clone()),
+                    error: Some(message),
+                    solve: false,
+                    objects: vec![],
+                    debug_images: None,
+                    adjustments: None,
+                },
+                vec![],
+                code,
+            )
+        }
+    };
+
+    if let Some(Some(access_log)) = ACCESS_LOG.get() {
+        access_log.write(&AccessEntry {
+            timestamp: access_log::timestamp(),
+            request_id: &request_id,
+            client_ip: remote.map(|addr| addr.ip()),
+            key,
+            model,
+            images,
+            answers: &result.objects,
+            confidence: &confidence,
+            latency_ms: start.elapsed().as_secs_f64() * 1000.0,
+            status: code.as_u16(),
+        });
+    }
+
+    let mut response = warp::reply::with_status(warp::reply::json(&result), code).into_response();
+    let headers = response.headers_mut();
+    if let Ok(value) = HeaderValue::from_str(&request_id) {
+        headers.insert(REQUEST_ID_HEADER, value);
+    }
+    if let Some(Some(_)) = ANSWER_CACHE.get() {
+        if result.solve {
+            let status = match cache_hits {
+                0 => "MISS",
+                hits if hits == result.objects.len() => "HIT",
+                _ => "PARTIAL",
+            };
+            headers.insert(CACHE_HEADER, HeaderValue::from_static(status));
+        }
+    }
+    Ok(response)
+}
+
+/// Solve the task, returns the prediction of every image in order
+async fn solve_task(request_id: &str, task: Task) -> Result<Vec<Solved>, Rejection> {
     // Check the API key
     check_api_key(task.api_key).await?;
     // Check the submit limit
     check_submit_limit(task.images.len()).await?;
 
     // Solve the task
-    match model::get_predictor(task.typed) {
-        Ok(predictor) => {
-            let objects = if task.images.len() == 1 {
-                let image = decode_image(&task.images[0])
-                    .map_err(|e| warp::reject::custom(BadRequest(e.to_string())))?;
-                let answer = predictor
-                    .predict(image)
-                    .map_err(|e| warp::reject::custom(BadRequest(e.to_string())))?;
-
-                vec![answer as u32]
-            } else {
-                let mut objects = task
-                    .images
-                    .into_par_iter()
-                    .enumerate()
-                    .map(|(index, image)| {
-                        // decode the image
-                        let image = decode_image(&image)?;
-                        let answer = predictor.predict(image)?;
-                        Ok((index, answer as u32))
-                    })
-                    .collect::<Result<Vec<(usize, u32)>>>()
-                    .map_err(|e| warp::reject::custom(BadRequest(e.to_string())))?;
-
-                objects.sort_by_key(|&(index, _)| index);
-                objects
-                    .into_iter()
-                    .map(|(_, answer)| answer)
-                    .collect::<Vec<u32>>()
-            };
+    let model = task.typed;
+    let debug = task.debug;
+    if !READY.initialized() {
+        return Err(warp::reject::custom(ServiceUnavailable(
+            "Models are loading".to_owned(),
+        )));
+    }
+    let predictor =
+        model::get_predictor(model).map_err(|e| warp::reject::custom(BadRequest(e.to_string())))?;
 
-            let result = TaskResult {
-                error: None,
-                solve: true,
-                objects,
-            };
-            return Ok(warp::reply::json(&result));
+    let pool = INFERENCE_POOL.get().ok_or_else(|| {
+        warp::reject::custom(InternalError(
+            "inference pool is not initialized".to_owned(),
+        ))
+    })?;
+    // pool threads do not inherit the current span
+    let span = tracing::Span::current();
+    let images = task.images;
+    let request = request_id.to_owned();
+    let predictions = pool
+        .run(move |cancellation| {
+            let _enter = span.enter();
+            predict_images(&request, model, predictor, images, debug, cancellation)
+        })
+        .await
+        .map_err(|err| match err {
+            PoolError::Full => {
+                warp::reject::custom(ServiceUnavailable("Inference queue is full".to_owned()))
+            }
+            PoolError::Failed => {
+                warp::reject::custom(InternalError("inference task failed".to_owned()))
+            }
+        })?
+        .map_err(|e| warp::reject::custom(BadRequest(e.to_string())))?;
+
+    // Record the request for feedback
+    if let Some(Some(capture)) = CAPTURE.get() {
+        let images = predictions
+            .iter()
+            .filter_map(|solved| Some((solved.key?, solved.prediction.answer)))
+            .collect::<Vec<(CacheKey, i32)>>();
+        if let Err(err) = capture.save_request(request_id, model, &images) {
+            tracing::warn!("failed to record request: {err}");
         }
-        Err(e) => {
-            return Err(warp::reject::custom(BadRequest(e.to_string())));
+    }
+
+    Ok(predictions)
+}
+
+/// Prediction of a single image
+struct Solved {
+    prediction: Prediction,
+    /// whether the answer came from the cache
+    cached: bool,
+    /// image hash, computed when the cache or capture is enabled
+    key: Option<CacheKey>,
+    /// base64 PNG of the crops and scores, if requested
+    debug_image: Option<String>,
+    /// changes made by input normalization
+    adjustments: Vec<Adjustment>,
+}
+
+/// Decode and predict the images in order, on the inference pool
+fn predict_images(
+    request_id: &str,
+    model: ModelType,
+    predictor: &'static dyn Predictor,
+    images: Vec<String>,
+    debug: bool,
+    cancellation: &Cancellation,
+) -> Result<Vec<Solved>> {
+    if images.len() == 1 {
+        cancellation.check()?;
+        return Ok(vec![predict_image(
+            request_id, model, predictor, &images[0], debug,
+        )?]);
+    }
+
+    // rayon workers do not inherit the current span
+    let span = tracing::Span::current();
+    let mut predictions = images
+        .into_par_iter()
+        .enumerate()
+        .map(|(index, image)| {
+            let _enter = span.enter();
+            cancellation.check()?;
+            Ok((
+                index,
+                predict_image(request_id, model, predictor, &image, debug)?,
+            ))
+        })
+        .collect::<Result<Vec<(usize, Solved)>>>()?;
+
+    predictions.sort_by_key(|(index, _)| *index);
+    Ok(predictions
+        .into_iter()
+        .map(|(_, solved)| solved)
+        .collect::<Vec<Solved>>())
+}
+
+/// Decode and predict a single image
+fn predict_image(
+    request_id: &str,
+    model: ModelType,
+    predictor: &dyn Predictor,
+    image: &str,
+    debug: bool,
+) -> Result<Solved> {
+    // decode the image
+    let background = BACKGROUND.get().copied().unwrap_or(Rgb([255, 255, 255]));
+    let image = decode::decode_base64(image, background)?;
+    let (image, adjustments) = match NORMALIZE_INPUT.get() {
+        Some(true) => model::normalize::normalize(image, &predictor.descriptor().geometry)?,
+        _ => (image, Vec::new()),
+    };
+
+    let cache = ANSWER_CACHE.get().and_then(Option::as_ref);
+    let capture = CAPTURE.get().and_then(Option::as_ref);
+    let key = (cache.is_some() || capture.is_some()).then(|| CacheKey::new(model, &image));
+
+    if let (Some(cache), Some(key)) = (cache, key) {
+        if let Some(prediction) = cache.get(&key) {
+            tracing::debug!(answer = prediction.answer, "answer cache hit");
+            let debug_image = debug
+                .then(|| debug_image(predictor, &image, &prediction))
+                .transpose()?;
+            return Ok(Solved {
+                prediction,
+                cached: true,
+                key: Some(key),
+                debug_image,
+                adjustments,
+            });
         }
     }
+
+    // The predictor consumes the image, keep a copy of the sampled or debugged ones
+    let sampled =
+        matches!((capture, key.as_ref()), (Some(capture), Some(key)) if capture.sampled(key));
+    let kept = (sampled || debug).then(|| image.clone());
+
+    let prediction = predictor.predict(image)?;
+    let debug_image = match (debug, kept.as_ref()) {
+        (true, Some(image)) => Some(debug_image(predictor, image, &prediction)?),
+        _ => None,
+    };
+    let captured = kept.filter(|_| sampled);
+
+    if let (Some(cache), Some(key)) = (cache, key) {
+        cache.insert(key, prediction.clone());
+    }
+    if let (Some(capture), Some(key), Some(image)) = (capture, key.as_ref(), captured) {
+        if let Err(err) = capture.save(request_id, key, &image, &prediction) {
+            tracing::warn!("failed to capture sample: {err}");
+        }
+    }
+    Ok(Solved {
+        prediction,
+        cached: false,
+        key,
+        debug_image,
+        adjustments,
+    })
+}
+
+/// Render the debug image of the prediction as base64 PNG
+fn debug_image(
+    predictor: &dyn Predictor,
+    image: &DynamicImage,
+    prediction: &Prediction,
+) -> Result<String> {
+    use base64::{engine::general_purpose, Engine as _};
+    let rendered = model::debug::render(
+        image,
+        &predictor.layout(image)?,
+        predictor.descriptor().preprocess.resize,
+        prediction,
+    );
+    Ok(general_purpose::STANDARD.encode(model::debug::encode_png(rendered)?))
+}
+
+/// Handle the true answers of an earlier task
+async fn handle_feedback(request_id: String, feedback: Feedback) -> Result<impl Reply, Rejection> {
+    check_api_key(feedback.api_key).await?;
+
+    let capture = CAPTURE.get().and_then(Option::as_ref).ok_or_else(|| {
+        warp::reject::custom(NotFound("Feedback requires --capture-dir".to_owned()))
+    })?;
+    let applied = capture
+        .feedback(&request_id, feedback.answers)
+        .map_err(|err| match err {
+            FeedbackError::Unknown => {
+                warp::reject::custom(NotFound(format!("Unknown request: {request_id}")))
+            }
+            FeedbackError::Reported => {
+                warp::reject::custom(Conflict(format!("Feedback already received: {request_id}")))
+            }
+            FeedbackError::Answers { expected, got } => warp::reject::custom(BadRequest(format!(
+                "Expected {expected} answers, got {got}"
+            ))),
+            FeedbackError::Internal(e) => warp::reject::custom(InternalError(e.to_string())),
+        })?;
+    tracing::info!(
+        request_id = %request_id,
+        correct = ?applied.correct,
+        captured = ?applied.captured,
+        "feedback received"
+    );
+    Ok(warp::reply::json(&FeedbackResult {
+        request_id,
+        correct: applied.correct,
+        captured: applied.captured,
+    }))
+}
+
+/// List the models with their feedback accuracy
+fn handle_models() -> impl Reply {
+    let accuracy = METRICS.accuracy();
+    let models = ModelType::ALL
+        .into_iter()
+        .map(|model| {
+            let feedback = accuracy.get(model.as_str()).copied().unwrap_or_default();
+            ModelInfo {
+                typed: model.as_str(),
+                loaded: model::get_predictor(model).is_ok(),
+                feedback: feedback.total,
+                correct: feedback.correct,
+                accuracy: feedback.ratio(),
+            }
+        })
+        .collect::<Vec<ModelInfo>>();
+    warp::reply::json(&models)
+}
+
+/// Readiness probe, every model loaded and warmed up
+fn handle_ready() -> impl Reply {
+    let ready = READY.initialized();
+    let code = match ready {
+        true => StatusCode::OK,
+        false => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    warp::reply::with_status(
+        warp::reply::json(&serde_json::json!({ "ready": ready })),
+        code,
+    )
 }
 
 /// Check the API key
@@ -148,19 +632,21 @@ async fn check_submit_limit(len: usize) -> Result<(), Rejection> {
     Ok(())
 }
 
-/// Decode the base64 image
-fn decode_image(base64_string: &String) -> Result<DynamicImage> {
-    // base64 decode the image
-    use base64::{engine::general_purpose, Engine as _};
-    let image_bytes = general_purpose::STANDARD
-        .decode(base64_string.split(',').nth(1).unwrap_or(base64_string))?;
-    // convert the bytes to an image
-    Ok(image::load_from_memory(&image_bytes)?)
-}
-
 #[derive(Debug)]
 struct BadRequest(String);
 
+#[derive(Debug)]
+struct NotFound(String);
+
+#[derive(Debug)]
+struct Conflict(String);
+
+#[derive(Debug)]
+struct InternalError(String);
+
+#[derive(Debug)]
+struct ServiceUnavailable(String);
+
 #[derive(Debug)]
 struct InvalidTApiKeyError;
 
@@ -169,13 +655,43 @@ struct InvalidSubmitLimitError;
 
 impl Reject for BadRequest {}
 
+impl Reject for NotFound {}
+
+impl Reject for Conflict {}
+
+impl Reject for InternalError {}
+
+impl Reject for ServiceUnavailable {}
+
 impl Reject for InvalidTApiKeyError {}
 
 impl Reject for InvalidSubmitLimitError {}
 
 impl Reject for TaskResult {}
 
-async fn handle_rejection(err: Rejection) -> Result<impl Reply, Infallible> {
+fn handle_rejection(err: &Rejection, request_id: String) -> warp::reply::Response {
+    let (code, message) = rejection_message(err);
+
+    let mut response = warp::reply::with_status(
+        warp::reply::json(&TaskResult {
+            request_id: Some(request_id.clone()),
+            error: Some(message),
+            solve: false,
+            objects: vec![],
+            debug_images: None,
+            adjustments: None,
+        }),
+        code,
+    )
+    .into_response();
+    if let Ok(value) = HeaderValue::from_str(&request_id) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    response
+}
+
+/// Map a rejection to the response status and error message
+fn rejection_message(err: &Rejection) -> (StatusCode, String) {
     let code;
     let message;
 
@@ -185,13 +701,26 @@ async fn handle_rejection(err: Rejection) -> Result<impl Reply, Infallible> {
     } else if let Some(e) = err.find::<BadRequest>() {
         code = StatusCode::BAD_REQUEST;
         message = e.0.to_owned();
-    } else if let Some(_) = err.find::<InvalidTApiKeyError>() {
+    } else if let Some(e) = err.find::<NotFound>() {
+        code = StatusCode::NOT_FOUND;
+        message = e.0.to_owned();
+    } else if let Some(e) = err.find::<Conflict>() {
+        code = StatusCode::CONFLICT;
+        message = e.0.to_owned();
+    } else if let Some(e) = err.find::<InternalError>() {
+        tracing::warn!("{}", e.0);
+        code = StatusCode::INTERNAL_SERVER_ERROR;
+        message = "Internal Server Error".to_owned();
+    } else if let Some(e) = err.find::<ServiceUnavailable>() {
+        code = StatusCode::SERVICE_UNAVAILABLE;
+        message = e.0.to_owned();
+    } else if err.find::<InvalidTApiKeyError>().is_some() {
         code = StatusCode::UNAUTHORIZED;
         message = "Invalid API key".to_owned();
     } else if let Some(e) = err.find::<BodyDeserializeError>() {
         code = StatusCode::BAD_REQUEST;
         message = e.to_string();
-    } else if let Some(_) = err.find::<InvalidSubmitLimitError>() {
+    } else if err.find::<InvalidSubmitLimitError>().is_some() {
         code = StatusCode::BAD_REQUEST;
         if let Some(limit) = SUBMIT_LIMIT.get() {
             message = format!("Invalid submit limit: {}", limit.unwrap_or(0));
@@ -204,11 +733,74 @@ async fn handle_rejection(err: Rejection) -> Result<impl Reply, Infallible> {
         message = "Internal Server Error".to_owned();
     }
 
-    let json = warp::reply::json(&TaskResult {
-        error: Some(message),
-        solve: false,
-        objects: vec![],
-    });
+    (code, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn invalid_task_bodies_reach_the_handler() {
+        let task = warp::test::request()
+            .method("POST")
+            .body(r#"{"type": "shadows", "images": ["aGk="]}"#)
+            .filter(&task_body())
+            .await
+            .unwrap();
+        assert_eq!(task.unwrap().typed, ModelType::Shadows);
+
+        for body in ["{", r#"{"type": "unknown", "images": []}"#] {
+            let err = warp::test::request()
+                .method("POST")
+                .body(body)
+                .filter(&task_body())
+                .await
+                .unwrap()
+                .unwrap_err();
+            assert_eq!(rejection_message(&err).0, StatusCode::BAD_REQUEST);
+        }
+    }
 
-    Ok(warp::reply::with_status(json, code))
+    #[tokio::test]
+    async fn rejections_carry_the_request_id() {
+        let routes = with_request_id(warp::path("models").and(warp::get()).map(|| "[]"));
+
+        let response = warp::test::request()
+            .path("/missing")
+            .header(REQUEST_ID_HEADER, "abc")
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(response.headers()[REQUEST_ID_HEADER], "abc");
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["request_id"], "abc");
+
+        // a generated ID is the same in the body and the header
+        let response = warp::test::request()
+            .method("POST")
+            .path("/models")
+            .reply(&routes)
+            .await;
+        assert!(!response.status().is_success());
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(
+            body["request_id"],
+            response.headers()[REQUEST_ID_HEADER].to_str().unwrap()
+        );
+
+        let response = warp::test::request()
+            .path("/models")
+            .header(REQUEST_ID_HEADER, "def")
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[REQUEST_ID_HEADER], "def");
+    }
+
+    #[test]
+    fn full_queue_is_unavailable() {
+        let err = warp::reject::custom(ServiceUnavailable("Inference queue is full".to_owned()));
+        assert_eq!(rejection_message(&err).0, StatusCode::SERVICE_UNAVAILABLE);
+    }
 }
@@ -0,0 +1,176 @@
+//! Dedicated inference thread pool, so decoding and prediction never block the tokio
+//! workers serving the connections
+
+use anyhow::Result;
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+use tokio::sync::{oneshot, Semaphore};
+
+use crate::metrics::METRICS;
+
+pub struct InferencePool {
+    pool: rayon::ThreadPool,
+    /// permits of the running and queued tasks
+    slots: Arc<Semaphore>,
+}
+
+/// Why a task did not run
+#[derive(Debug)]
+pub enum PoolError {
+    /// every thread is busy and the queue is full
+    Full,
+    /// the task panicked
+    Failed,
+}
+
+/// Set when the client is gone, checked by the task between images
+#[derive(Clone, Default)]
+pub struct Cancellation(Arc<AtomicBool>);
+
+impl Cancellation {
+    pub fn check(&self) -> Result<()> {
+        if self.0.load(Ordering::Relaxed) {
+            anyhow::bail!("Cancelled: the client disconnected");
+        }
+        Ok(())
+    }
+}
+
+/// Cancels the task when the awaiting request is dropped
+struct CancelOnDrop(Cancellation);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0 .0.store(true, Ordering::Relaxed);
+    }
+}
+
+impl InferencePool {
+    /// Pool of `threads` threads, the available cores if 0, and `queue` waiting tasks
+    pub fn new(threads: usize, queue: usize) -> Result<Self> {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .thread_name(|i| format!("fcsrv-inference-{i}"))
+            .panic_handler(|_| tracing::error!("inference task panicked"))
+            .build()?;
+        tracing::info!(
+            threads = pool.current_num_threads(),
+            queue,
+            "inference pool"
+        );
+        Ok(Self {
+            slots: Arc::new(Semaphore::new(pool.current_num_threads() + queue)),
+            pool,
+        })
+    }
+
+    /// Run the task on the pool and wait for its result. Parallel iterators of the task
+    /// run on the pool too. The task is skipped if the request is dropped while queued.
+    pub async fn run<F, T>(&self, task: F) -> Result<T, PoolError>
+    where
+        F: FnOnce(&Cancellation) -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let Ok(permit) = self.slots.clone().try_acquire_owned() else {
+            METRICS.inference_rejected.fetch_add(1, Ordering::Relaxed);
+            return Err(PoolError::Full);
+        };
+        let cancellation = Cancellation::default();
+        let _guard = CancelOnDrop(cancellation.clone());
+        let (tx, rx) = oneshot::channel();
+        let queued = Instant::now();
+        self.pool.spawn(move || {
+            let _permit = permit;
+            METRICS.queue_wait.observe(queued.elapsed());
+            if cancellation.check().is_err() {
+                METRICS.inference_cancelled.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+            let _ = tx.send(task(&cancellation));
+        });
+        rx.await.map_err(|_| PoolError::Failed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    fn queue_wait_count() -> u64 {
+        METRICS
+            .render()
+            .lines()
+            .find_map(|line| line.strip_prefix("fcsrv_inference_queue_wait_seconds_count "))
+            .and_then(|count| count.parse().ok())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn rejects_past_threads_and_queue() {
+        let pool = Arc::new(InferencePool::new(1, 1).unwrap());
+        let (started_tx, started_rx) = oneshot::channel();
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+        let running = tokio::spawn({
+            let pool = pool.clone();
+            async move {
+                pool.run(move |_| {
+                    let _ = started_tx.send(());
+                    let _ = release_rx.recv();
+                })
+                .await
+            }
+        });
+        started_rx.await.unwrap();
+        let queued = tokio::spawn({
+            let pool = pool.clone();
+            async move { pool.run(|_| 1).await }
+        });
+        while pool.slots.available_permits() > 0 {
+            tokio::task::yield_now().await;
+        }
+
+        assert!(matches!(pool.run(|_| ()).await, Err(PoolError::Full)));
+        release_tx.send(()).unwrap();
+        assert!(running.await.unwrap().is_ok());
+        assert_eq!(queued.await.unwrap().unwrap(), 1);
+        assert_eq!(pool.slots.available_permits(), 2);
+    }
+
+    #[tokio::test]
+    async fn dropped_requests_cancel_the_task() {
+        let pool = Arc::new(InferencePool::new(1, 0).unwrap());
+        let (cancellation_tx, cancellation_rx) = oneshot::channel();
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+        let request = tokio::spawn({
+            let pool = pool.clone();
+            async move {
+                pool.run(move |cancellation| {
+                    let _ = cancellation_tx.send(cancellation.clone());
+                    let _ = release_rx.recv();
+                })
+                .await
+            }
+        });
+        let cancellation = cancellation_rx.await.unwrap();
+        assert!(cancellation.check().is_ok());
+
+        request.abort();
+        assert!(request.await.unwrap_err().is_cancelled());
+        assert!(cancellation.check().is_err());
+        release_tx.send(()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn records_the_queue_wait() {
+        let pool = InferencePool::new(1, 0).unwrap();
+        let before = queue_wait_count();
+        pool.run(|_| ()).await.unwrap();
+        assert!(queue_wait_count() > before);
+    }
+}
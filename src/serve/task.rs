@@ -1,4 +1,4 @@
-use crate::model::ModelType;
+use crate::model::{normalize::Adjustment, ModelType};
 use anyhow::Error as AnyhowError;
 use image::ImageError;
 use serde::{Deserialize, Serialize};
@@ -12,10 +12,16 @@ pub struct Task {
     pub typed: ModelType,
     /// base64 image list, e.g. ["/9j/4AAQSkZJRgABAQAAAQABAAD/2wBDAAgGBgcGBQgHBwcJCQgKDBQNDAsLDBkS"]
     pub images: Vec<String>,
+    /// return an annotated image of the crops and scores of every image
+    #[serde(default)]
+    pub debug: bool,
 }
 
 #[derive(Debug, Serialize)]
 pub struct TaskResult {
+    /// request ID, taken from `X-Request-Id` or generated
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
     /// error message, if any
     #[serde(skip_serializing_if = "Option::is_none")]
     pub error: Option<String>,
@@ -23,23 +29,35 @@ pub struct TaskResult {
     pub solve: bool,
     /// whether the model is a classifier
     pub objects: Vec<u32>,
+    /// base64 PNG debug image of every image, if requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub debug_images: Option<Vec<String>>,
+    /// input normalization changes of every image, if any image was changed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub adjustments: Option<Vec<Vec<Adjustment>>>,
 }
 
 impl From<ImageError> for TaskResult {
     fn from(result: ImageError) -> Self {
         Self {
+            request_id: None,
             error: Some(result.to_string()),
             solve: false,
             objects: vec![],
+            debug_images: None,
+            adjustments: None,
         }
     }
 }
 impl From<base64::DecodeError> for TaskResult {
     fn from(result: base64::DecodeError) -> Self {
         Self {
+            request_id: None,
             error: Some(result.to_string()),
             solve: false,
             objects: vec![],
+            debug_images: None,
+            adjustments: None,
         }
     }
 }
@@ -47,9 +65,46 @@ impl From<base64::DecodeError> for TaskResult {
 impl From<AnyhowError> for TaskResult {
     fn from(err: AnyhowError) -> Self {
         Self {
+            request_id: None,
             error: Some(err.to_string()),
             solve: false,
             objects: vec![],
+            debug_images: None,
+            adjustments: None,
         }
     }
 }
+
+#[derive(Debug, Deserialize)]
+pub struct Feedback {
+    /// API key
+    pub api_key: Option<String>,
+    /// true answer of every image, in request order
+    pub answers: Vec<u32>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FeedbackResult {
+    /// request ID of the task
+    pub request_id: String,
+    /// whether each predicted answer was correct
+    pub correct: Vec<bool>,
+    /// whether each image was captured, only captured images are relabeled
+    pub captured: Vec<bool>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ModelInfo {
+    /// model type, e.g. 3d_rollball_animals
+    #[serde(rename = "type")]
+    pub typed: &'static str,
+    /// whether the model is loaded
+    pub loaded: bool,
+    /// answers reported through feedback
+    pub feedback: u64,
+    /// reported answers that matched the prediction
+    pub correct: u64,
+    /// ratio of correct answers, if any feedback
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub accuracy: Option<f64>,
+}